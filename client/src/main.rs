@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fmt::{self, Display},
-    io::{BufRead, ErrorKind, Read},
+    io::{ErrorKind, Read},
     net::SocketAddr,
     sync::Arc,
 };
@@ -15,13 +15,13 @@ use rustyline_async::ReadlineEvent;
 use tokio::{
     io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
     net::{tcp, TcpStream, ToSocketAddrs},
-    sync::{mpsc, Mutex},
+    sync::{mpsc, watch, Mutex},
     task::JoinHandle,
 };
 use tracing::{debug, info, warn};
 
-const AXES: usize = 4;
-const AXIS_LABELS: [char; AXES] = ['X', 'Z', 'C', 'F'];
+const AXES: usize = 3;
+const AXIS_LABELS: [char; AXES] = ['X', 'Z', 'C'];
 
 #[derive(clap::Subcommand, Debug)]
 enum Command {
@@ -57,40 +57,41 @@ struct Args {
     command: Command,
 }
 
-fn read_commands(input: impl Read, verify: bool) -> Result<Vec<String>> {
-    let reader = std::io::BufReader::new(input);
-    let mut res = Vec::new();
+fn read_commands(mut input: impl Read, verify: bool) -> Result<Vec<String>> {
+    let mut source = String::new();
+    input.read_to_string(&mut source)?;
     if !verify {
-        for line in reader.lines() {
-            res.push(line?);
-        }
-    } else {
-        for (i, line) in reader.lines().enumerate() {
-            let line = line?;
-            let line = line.trim();
-            if line.starts_with('(') {
-                if !line.ends_with(')') {
-                    bail!("line {i}: Mismatched parenthesis in comment");
-                }
-                continue;
-            }
-            let line = match line.rsplit_once(';') {
-                Some((line, _comment)) => line,
-                None => line,
-            };
-            let mut line = line.to_owned();
-            if !line.ends_with('\n') {
-                line.push('\n');
+        return Ok(source.lines().map(str::to_owned).collect());
+    }
+
+    match gcode::parse_program(AXIS_LABELS, &source) {
+        Ok(lines) => Ok(lines
+            .into_iter()
+            .filter(|line| line.line.command != gcode::Command::Comment)
+            .map(|line| source[line.span].to_owned())
+            .collect()),
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{error}: \"{}\"", source[error.line_span.clone()].trim());
             }
-            match gcode::parse_single_command(AXIS_LABELS, line.as_bytes()) {
-                Ok(_) => {}
-                Err(_) => bail!("line {i}: Invalid gcode command: \"{}\"", line.trim()),
-            };
-            res.push(line);
+            bail!("Invalid gcode program ({} errors)", errors.len())
         }
     }
+}
 
-    Ok(res)
+/// Whether the given line is an M400, which the client waits on before sending anything else
+fn waits_for_motion(command: &str) -> bool {
+    let line = format!("{}\n", command.trim_end());
+    matches!(
+        gcode::parse_single_command(AXIS_LABELS, line.as_bytes()),
+        Ok((
+            _,
+            gcode::Line {
+                command: gcode::Command::WaitForMotion,
+                ..
+            }
+        ))
+    )
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -108,10 +109,257 @@ pub struct Ack(Option<CommandId>);
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Done(CommandId);
 
+/// A command the server accepted, but then couldn't carry out, reported in place of its done. One
+/// run by a subroutine call or loop has no id of its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failed(Option<CommandId>, String);
+
+impl Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(id) => write!(f, "command {id} failed: {}", self.1),
+            None => write!(f, "command failed: {}", self.1),
+        }
+    }
+}
+
+/// Request from the server to resend the given line, because the line it got was corrupted or out
+/// of sequence
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resend(u32);
+
+/// Why the server didn't accept a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    Resend(Resend),
+    /// The line was valid gcode, but the server couldn't act on it (eg, calling an undefined
+    /// subroutine)
+    Error(String),
+}
+
+/// The position of the machine, reported by the server in response to M114
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    /// Each axis label (and `F`, for the feedrate), along with its value in the active units
+    coords: Vec<(String, f64)>,
+    units: gcode::Units,
+}
+
+/// Parse a list of `(label value)` pairs, ending with `(units mm)` or `(units in)`
+fn labeled_values(value: &lexpr::Value) -> Option<(Vec<(String, f64)>, gcode::Units)> {
+    let mut values = Vec::new();
+    let mut units = None;
+    for word in value.list_iter()?.skip(1) {
+        match (word.get(0)?.as_symbol()?, word.get(1)?) {
+            ("units", units_value) => {
+                units = Some(match units_value.as_symbol()? {
+                    "in" => gcode::Units::Inches,
+                    "mm" => gcode::Units::Millimeters,
+                    _ => return None,
+                })
+            }
+            (label, value) => values.push((label.to_owned(), value.as_f64()?)),
+        }
+    }
+    Some((values, units?))
+}
+
+fn units_name(units: gcode::Units) -> &'static str {
+    match units {
+        gcode::Units::Inches => "in",
+        gcode::Units::Millimeters => "mm",
+    }
+}
+
+impl Position {
+    fn from_sexp(value: &lexpr::Value) -> Option<Self> {
+        let (coords, units) = labeled_values(value)?;
+        Some(Self { coords, units })
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, coord) in &self.coords {
+            write!(f, "{label}{coord} ")?;
+        }
+        write!(f, "({})", units_name(self.units))
+    }
+}
+
+/// The calibration of each axis, reported by the server in response to M92 without any axes
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepsPerUnit {
+    /// Each axis label, along with its steps per unit (or per rotation, for rotational axes)
+    steps: Vec<(String, f64)>,
+    units: gcode::Units,
+}
+
+impl StepsPerUnit {
+    fn from_sexp(value: &lexpr::Value) -> Option<Self> {
+        let (steps, units) = labeled_values(value)?;
+        Some(Self { steps, units })
+    }
+}
+
+impl Display for StepsPerUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, steps) in &self.steps {
+            write!(f, "{label}{steps} ")?;
+        }
+        write!(f, "(steps per {})", units_name(self.units))
+    }
+}
+
+/// Which limit switches are triggered, reported by the server in response to M119. Axes without a
+/// limit switch are left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endstops(Vec<(String, bool)>);
+
+impl Endstops {
+    fn from_sexp(value: &lexpr::Value) -> Option<Self> {
+        value
+            .list_iter()?
+            .skip(1)
+            .map(|endstop| {
+                let triggered = match endstop.get(1)?.as_symbol()? {
+                    "triggered" => true,
+                    "open" => false,
+                    _ => return None,
+                };
+                Some((endstop.get(0)?.as_symbol()?.to_owned(), triggered))
+            })
+            .collect::<Option<_>>()
+            .map(Self)
+    }
+}
+
+impl Display for Endstops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (label, triggered)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            let state = if *triggered { "triggered" } else { "open" };
+            write!(f, "{label}: {state}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the firmware is and what it supports, reported by the server in response to M115
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    name: String,
+    version: String,
+    build: String,
+    axes: Vec<String>,
+    units: Vec<gcode::Units>,
+    /// How many commands the firmware can queue up
+    buffer: usize,
+    /// The G- and M-codes it understands
+    codes: Vec<String>,
+}
+
+impl FirmwareInfo {
+    fn from_sexp(value: &lexpr::Value) -> Option<Self> {
+        let mut name = None;
+        let mut version = None;
+        let mut build = None;
+        let mut axes = None;
+        let mut units = None;
+        let mut buffer = None;
+        let mut codes = None;
+        let symbols = |value: &lexpr::Value| -> Option<Vec<String>> {
+            value
+                .list_iter()?
+                .skip(1)
+                .map(|symbol| Some(symbol.as_symbol()?.to_owned()))
+                .collect()
+        };
+        for field in value.list_iter()?.skip(1) {
+            match field.get(0)?.as_symbol()? {
+                "name" => name = Some(field.get(1)?.as_str()?.to_owned()),
+                "version" => version = Some(field.get(1)?.as_str()?.to_owned()),
+                "build" => build = Some(field.get(1)?.as_str()?.to_owned()),
+                "axes" => axes = Some(symbols(field)?),
+                "units" => {
+                    units = Some(
+                        symbols(field)?
+                            .iter()
+                            .map(|units| match units.as_str() {
+                                "in" => Some(gcode::Units::Inches),
+                                "mm" => Some(gcode::Units::Millimeters),
+                                _ => None,
+                            })
+                            .collect::<Option<_>>()?,
+                    )
+                }
+                "buffer" => buffer = Some(usize::try_from(field.get(1)?.as_u64()?).ok()?),
+                "codes" => codes = Some(symbols(field)?),
+                // Ignore anything newer firmware adds
+                _ => {}
+            }
+        }
+        Some(Self {
+            name: name?,
+            version: version?,
+            build: build?,
+            axes: axes?,
+            units: units?,
+            buffer: buffer?,
+            codes: codes?,
+        })
+    }
+
+    /// Check that the firmware can run the programs this client sends. Its axes have to match the
+    /// ones programs are parsed with, and any codes it doesn't know are worth a warning
+    fn check_compatible(&self) -> Result<()> {
+        if self.axes != AXIS_LABELS.map(String::from) {
+            bail!(
+                "firmware has axes {:?}, but this client expects {AXIS_LABELS:?}",
+                self.axes
+            );
+        }
+        let unsupported = gcode::SUPPORTED_CODES
+            .iter()
+            .filter(|code| !self.codes.iter().any(|c| c == *code))
+            .collect::<Vec<_>>();
+        if !unsupported.is_empty() {
+            warn!(
+                ?unsupported,
+                "firmware doesn't support every code this client does, so lines using them will be rejected"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Display for FirmwareInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}), axes {}, buffer {}",
+            self.name,
+            self.version,
+            self.build,
+            self.axes.join(""),
+            self.buffer
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Response {
     Ack(Ack),
     Done(Done),
+    Failed(Failed),
+    Resend(Resend),
+    Position(Position),
+    StepsPerUnit(StepsPerUnit),
+    Endstops(Endstops),
+    FirmwareInfo(FirmwareInfo),
+    Error(String),
 }
 
 impl Response {
@@ -141,6 +389,63 @@ impl Response {
                     None => Err(value),
                 }
             }
+            Some(Value::Symbol(s)) if s.as_ref() == "failed" => {
+                let failed = match (value.get(1), value.get(2)) {
+                    (Some(message), None) => message
+                        .as_str()
+                        .map(|message| Failed(None, message.to_owned())),
+                    (Some(id), Some(message)) => id
+                        .as_number()
+                        .and_then(|v| v.as_u64())
+                        .and_then(|v| u32::try_from(v).ok())
+                        .zip(message.as_str())
+                        .map(|(id, message)| Failed(Some(CommandId(id)), message.to_owned())),
+                    _ => None,
+                };
+                match failed {
+                    Some(failed) => Ok(Self::Failed(failed)),
+                    None => Err(value),
+                }
+            }
+            Some(Value::Symbol(s)) if s.as_ref() == "resend" => {
+                match value
+                    .get(1)
+                    .and_then(|v| v.as_number())
+                    .and_then(|v| v.as_u64())
+                    .and_then(|v| u32::try_from(v).ok())
+                {
+                    Some(line_number) => Ok(Self::Resend(Resend(line_number))),
+                    None => Err(value),
+                }
+            }
+            Some(Value::Symbol(s)) if s.as_ref() == "position" => match Position::from_sexp(&value)
+            {
+                Some(position) => Ok(Self::Position(position)),
+                None => Err(value),
+            },
+            Some(Value::Symbol(s)) if s.as_ref() == "steps-per-unit" => {
+                match StepsPerUnit::from_sexp(&value) {
+                    Some(steps) => Ok(Self::StepsPerUnit(steps)),
+                    None => Err(value),
+                }
+            }
+            Some(Value::Symbol(s)) if s.as_ref() == "endstops" => match Endstops::from_sexp(&value)
+            {
+                Some(endstops) => Ok(Self::Endstops(endstops)),
+                None => Err(value),
+            },
+            Some(Value::Symbol(s)) if s.as_ref() == "firmware" => {
+                match FirmwareInfo::from_sexp(&value) {
+                    Some(info) => Ok(Self::FirmwareInfo(info)),
+                    None => Err(value),
+                }
+            }
+            Some(Value::Symbol(s)) if s.as_ref() == "error" => {
+                match value.get(1).and_then(|v| v.as_str()) {
+                    Some(message) => Ok(Self::Error(message.to_owned())),
+                    None => Err(value),
+                }
+            }
             None => {
                 if value.as_str() == Some("ack") {
                     Ok(Self::Ack(Ack(None)))
@@ -174,21 +479,126 @@ mod response_tests {
             Response::Done(Done(CommandId(8)))
         );
     }
+
+    #[test]
+    fn failed() {
+        assert_eq!(
+            resp_from_sexp("(failed 8 \"can't park before homing\")"),
+            Response::Failed(Failed(
+                Some(CommandId(8)),
+                "can't park before homing".to_owned()
+            ))
+        );
+        assert_eq!(
+            resp_from_sexp("(failed \"can't park before homing\")"),
+            Response::Failed(Failed(None, "can't park before homing".to_owned()))
+        );
+    }
+
+    #[test]
+    fn position() {
+        assert_eq!(
+            resp_from_sexp("(position (X 1.5) (Z 0) (C -2) (F 20) (units in))"),
+            Response::Position(Position {
+                coords: vec![
+                    ("X".to_owned(), 1.5),
+                    ("Z".to_owned(), 0.0),
+                    ("C".to_owned(), -2.0),
+                    ("F".to_owned(), 20.0)
+                ],
+                units: gcode::Units::Inches,
+            })
+        );
+    }
+
+    #[test]
+    fn steps_per_unit() {
+        assert_eq!(
+            resp_from_sexp("(steps-per-unit (X 83.333) (Z 166.666) (C 3200) (units mm))"),
+            Response::StepsPerUnit(StepsPerUnit {
+                steps: vec![
+                    ("X".to_owned(), 83.333),
+                    ("Z".to_owned(), 166.666),
+                    ("C".to_owned(), 3200.0),
+                ],
+                units: gcode::Units::Millimeters,
+            })
+        );
+    }
+
+    #[test]
+    fn endstops() {
+        assert_eq!(
+            resp_from_sexp("(endstops (X triggered) (Z open))"),
+            Response::Endstops(Endstops(vec![
+                ("X".to_owned(), true),
+                ("Z".to_owned(), false)
+            ]))
+        );
+        assert_eq!(
+            resp_from_sexp("(endstops)"),
+            Response::Endstops(Endstops(vec![]))
+        );
+    }
+
+    #[test]
+    fn resend() {
+        assert_eq!(resp_from_sexp("(resend 12)"), Response::Resend(Resend(12)));
+    }
+
+    #[test]
+    fn error() {
+        assert_eq!(
+            resp_from_sexp(r#"(error "undefined subroutine 7")"#),
+            Response::Error("undefined subroutine 7".to_owned())
+        );
+    }
+
+    #[test]
+    fn firmware_info() {
+        let info = |axes: &str| {
+            let Response::FirmwareInfo(info) = resp_from_sexp(&format!(
+                r#"(firmware (name "coil-winder") (version "0.1.0") (build "release") (axes {axes}) (units mm in) (buffer 32) (codes G0 G1 M115) (future stuff))"#
+            )) else {
+                panic!("expected firmware info");
+            };
+            info
+        };
+        assert_eq!(
+            info("X Z C"),
+            FirmwareInfo {
+                name: "coil-winder".to_owned(),
+                version: "0.1.0".to_owned(),
+                build: "release".to_owned(),
+                axes: vec!["X".to_owned(), "Z".to_owned(), "C".to_owned()],
+                units: vec![gcode::Units::Millimeters, gcode::Units::Inches],
+                buffer: 32,
+                codes: vec!["G0".to_owned(), "G1".to_owned(), "M115".to_owned()],
+            }
+        );
+        // Missing codes are only a warning, but the axes have to match
+        assert!(info("X Z C").check_compatible().is_ok());
+        assert!(info("X Y Z").check_compatible().is_err());
+    }
 }
 
 pub struct Client {
     addr: SocketAddr,
-    ack_rx: mpsc::Receiver<Ack>,
-    ack_tx: mpsc::Sender<Ack>,
-    done_tx: mpsc::UnboundedSender<Done>,
+    ack_rx: mpsc::Receiver<Result<Ack, Rejection>>,
+    ack_tx: mpsc::Sender<Result<Ack, Rejection>>,
+    done_tx: mpsc::UnboundedSender<Result<Done, Failed>>,
+    info_rx: mpsc::UnboundedReceiver<FirmwareInfo>,
+    info_tx: mpsc::UnboundedSender<FirmwareInfo>,
     writer: tcp::OwnedWriteHalf,
     reader: JoinHandle<()>,
+    /// Line number of the last line sent on this connection
+    line_number: u32,
 }
 
 impl Client {
     pub async fn connect(
         addr: impl ToSocketAddrs,
-    ) -> Result<(Self, mpsc::UnboundedReceiver<Done>)> {
+    ) -> Result<(Self, mpsc::UnboundedReceiver<Result<Done, Failed>>)> {
         let stream = TcpStream::connect(addr).await?;
         stream.set_nodelay(true)?;
         let addr = stream.peer_addr()?;
@@ -198,7 +608,9 @@ impl Client {
 
         let (ack_tx, ack_rx) = mpsc::channel(1);
         let (done_tx, done_rx) = mpsc::unbounded_channel();
-        let reader = Self::spawn_reader(buf_reader, ack_tx.clone(), done_tx.clone());
+        let (info_tx, info_rx) = mpsc::unbounded_channel();
+        let reader =
+            Self::spawn_reader(buf_reader, ack_tx.clone(), done_tx.clone(), info_tx.clone());
 
         Ok((
             Self {
@@ -206,8 +618,11 @@ impl Client {
                 ack_tx,
                 ack_rx,
                 done_tx,
+                info_rx,
+                info_tx,
                 writer,
                 reader,
+                line_number: 0,
             },
             done_rx,
         ))
@@ -215,27 +630,61 @@ impl Client {
 
     fn spawn_reader(
         buf_reader: BufReader<tcp::OwnedReadHalf>,
-        ack_tx: mpsc::Sender<Ack>,
-        done_tx: mpsc::UnboundedSender<Done>,
+        ack_tx: mpsc::Sender<Result<Ack, Rejection>>,
+        done_tx: mpsc::UnboundedSender<Result<Done, Failed>>,
+        info_tx: mpsc::UnboundedSender<FirmwareInfo>,
     ) -> JoinHandle<()> {
         tokio::spawn(async move {
             let mut lines = buf_reader.lines();
             while let Ok(Some(line)) = lines.next_line().await {
                 debug!(line, "got line from server");
-                match lexpr::from_str(&line.trim().trim_matches('\0').trim()) {
+                match lexpr::from_str(line.trim().trim_matches('\0').trim()) {
                     Err(err) => {
                         warn!(%err, "Invalid s-expression from server");
                     }
                     Ok(value) => match Response::from_sexp(value) {
                         Ok(Response::Ack(ack)) => {
                             debug!(?ack);
-                            if let Err(error) = ack_tx.send(ack).await {
+                            if let Err(error) = ack_tx.send(Ok(ack)).await {
                                 warn!(%error, "ack_tx send error")
                             }
                         }
+                        Ok(Response::Resend(resend)) => {
+                            debug!(?resend);
+                            if let Err(error) = ack_tx.send(Err(Rejection::Resend(resend))).await {
+                                warn!(%error, "ack_tx send error")
+                            }
+                        }
+                        Ok(Response::Error(message)) => {
+                            debug!(message, "error");
+                            if let Err(error) = ack_tx.send(Err(Rejection::Error(message))).await {
+                                warn!(%error, "ack_tx send error")
+                            }
+                        }
+                        Ok(Response::Position(position)) => {
+                            info!(%position, "position");
+                        }
+                        Ok(Response::StepsPerUnit(steps)) => {
+                            info!(%steps, "steps per unit");
+                        }
+                        Ok(Response::Endstops(endstops)) => {
+                            info!(%endstops, "endstops");
+                        }
+                        Ok(Response::FirmwareInfo(firmware)) => {
+                            info!(%firmware, "firmware");
+                            if let Err(error) = info_tx.send(firmware) {
+                                warn!(%error, "info_tx send error");
+                            }
+                        }
                         Ok(Response::Done(done)) => {
                             debug!(?done);
-                            if let Err(error) = done_tx.send(done) {
+                            if let Err(error) = done_tx.send(Ok(done)) {
+                                warn!(%error, "done_tx send error");
+                            }
+                        }
+                        Ok(Response::Failed(failed)) => {
+                            warn!(%failed);
+                            if let Err(error) = done_tx.send(Err(failed)) {
                                 warn!(%error, "done_tx send error");
                             }
                         }
@@ -256,40 +705,91 @@ impl Client {
         let buf_reader = BufReader::new(reader);
 
         self.reader.abort();
-        self.reader = Self::spawn_reader(buf_reader, self.ack_tx.clone(), self.done_tx.clone());
+        self.reader = Self::spawn_reader(
+            buf_reader,
+            self.ack_tx.clone(),
+            self.done_tx.clone(),
+            self.info_tx.clone(),
+        );
 
         self.writer = writer;
 
         Ok(())
     }
 
-    pub async fn send(&mut self, mut command: String) -> Result<Ack> {
-        if !command.ends_with('\n') {
-            command.push('\n');
-        }
+    /// Ask the firmware what it is and what it supports, with M115
+    pub async fn firmware_info(&mut self) -> Result<FirmwareInfo> {
+        self.send("M115".to_owned()).await?;
+        // The server reports it before acking
+        self.info_rx
+            .try_recv()
+            .map_err(|_| eyre!("server acked M115 without reporting firmware info"))
+    }
+
+    pub async fn send(&mut self, command: String) -> Result<Ack> {
+        let command = strip_line_comment(command.trim_end());
+        self.line_number += 1;
 
         loop {
-            match self.writer.write_all(command.as_bytes()).await {
-                Ok(()) => break,
+            let line = number_line(self.line_number, command);
+            match self.writer.write_all(line.as_bytes()).await {
+                Ok(()) => {}
                 Err(err) => match err.kind() {
                     ErrorKind::ConnectionReset
                     | ErrorKind::ConnectionAborted
                     | ErrorKind::BrokenPipe
                     | ErrorKind::NotConnected => {
                         self.reconnect().await?;
+                        // Line numbers start over on a new connection
+                        self.line_number = 1;
                         continue;
                     }
                     _ => return Err(err.into()),
                 },
             }
+            self.writer.flush().await?;
+
+            match self
+                .ack_rx
+                .recv()
+                .await
+                .ok_or_else(|| eyre!("ack channel closed"))?
+            {
+                Ok(ack) => return Ok(ack),
+                Err(Rejection::Resend(Resend(line_number))) if line_number == self.line_number => {
+                    warn!(line_number, "server requested resend");
+                }
+                Err(Rejection::Resend(Resend(line_number))) => bail!(
+                    "server requested resend of line {line_number}, but the last line sent was {}",
+                    self.line_number
+                ),
+                Err(Rejection::Error(message)) => {
+                    bail!("server rejected line {}: {message}", self.line_number)
+                }
+            }
         }
-        self.writer.flush().await?;
+    }
+}
 
-        self.ack_rx
-            .recv()
-            .await
-            .ok_or_else(|| eyre!("ack channel closed"))
+/// Strip a trailing `;` comment from the given line, so that a checksum can go at the end of it
+fn strip_line_comment(line: &str) -> &str {
+    let mut in_comment = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            ';' if !in_comment => return line[..i].trim_end(),
+            _ => {}
+        }
     }
+    line
+}
+
+/// Prefix the given command with a line number, and suffix it with a checksum
+fn number_line(line_number: u32, command: &str) -> String {
+    let line = format!("N{line_number} {command}");
+    let checksum = gcode::checksum(line.as_bytes());
+    format!("{line}*{checksum}\n")
 }
 
 #[tokio::main]
@@ -297,21 +797,22 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
     let (mut client, mut done_rx) = Client::connect(args.addr).await?;
+    client.firmware_info().await?.check_compatible()?;
 
     match args.command {
         Command::Oneshot { command } => {
-            let ack = client.send(command).await?;
-            let res = done_rx.recv().await;
-            debug!(?res);
-            match res {
-                Some(Done(CommandId(id))) => match ack.0 {
-                    None => info!(id, "done"),
-                    Some(CommandId(ack_id)) if ack_id == id => info!(id, "done"),
-                    Some(CommandId(ack_id)) => {
+            // Commands acked without an id (like M0 and M112) are done as soon as they're acked
+            if let Ack(Some(CommandId(ack_id))) = client.send(command).await? {
+                let res = done_rx.recv().await;
+                debug!(?res);
+                match res {
+                    Some(Ok(Done(CommandId(id)))) if id == ack_id => info!(id, "done"),
+                    Some(Ok(Done(CommandId(id)))) => {
                         bail!("got different done id ({id}) than ack id ({ack_id})??")
                     }
-                },
-                None => bail!("command_rx closed"),
+                    Some(Err(failed)) => bail!("{failed}"),
+                    None => bail!("command_rx closed"),
+                }
             }
             println!("ok");
             Ok(())
@@ -338,23 +839,34 @@ async fn main() -> Result<()> {
             );
 
             let sent_commands = Arc::new(Mutex::new(HashMap::<CommandId, String>::new()));
+            // Commands are done in the order they're sent, so this is everything up to the last
+            // done command
+            let (last_done_tx, mut last_done_rx) = watch::channel(0);
 
-            let done_progress = tokio::spawn({
+            let done_progress: JoinHandle<Result<()>> = tokio::spawn({
                 let sent_commands = Arc::clone(&sent_commands);
                 let run_bar = Arc::clone(&run_bar);
                 async move {
-                    while let Some(Done(command_id)) = done_rx.recv().await {
+                    while let Some(done) = done_rx.recv().await {
+                        let Done(command_id) = done.map_err(|failed| eyre!("{failed}"))?;
                         if let Some(command) = sent_commands.lock().await.remove(&command_id) {
                             run_bar.set_message(command.trim().to_owned())
                         } else {
                             warn!(%command_id, "unexpected command id in done msg from server");
                         }
                         run_bar.inc(1);
+                        last_done_tx.send_replace(command_id.0);
                     }
+                    Ok(())
                 }
             });
 
             for command in commands {
+                // A command failing stops the run
+                if done_progress.is_finished() {
+                    done_progress.await??;
+                    bail!("server stopped reporting commands as done");
+                }
                 upload_bar.set_message(command.trim().to_owned());
                 match client.send(command.clone()).await? {
                     Ack(None) => {
@@ -362,13 +874,20 @@ async fn main() -> Result<()> {
                         run_bar.inc(1)
                     }
                     Ack(Some(command_id)) => {
+                        let wait = waits_for_motion(&command);
                         sent_commands.lock().await.insert(command_id, command);
+                        if wait {
+                            // Only fails once a command has, which is reported above or below
+                            let _ = last_done_rx
+                                .wait_for(|&last_done| last_done >= command_id.0)
+                                .await;
+                        }
                     }
                 }
                 upload_bar.inc(1);
             }
 
-            done_progress.await?;
+            done_progress.await??;
 
             println!("Successfully ran {n} commands");
             Ok(())
@@ -386,3 +905,37 @@ async fn main() -> Result<()> {
         },
     }
 }
+
+#[cfg(test)]
+mod send_tests {
+    use super::*;
+
+    #[test]
+    fn numbered_lines_parse() {
+        let line = number_line(12, "G0 X1 (to the start)");
+        let (_, line) = gcode::parse_single_command(AXIS_LABELS, line.as_bytes()).unwrap();
+        assert_eq!(line.number, Some(12));
+    }
+
+    #[test]
+    fn read_program() {
+        let commands = read_commands("(start)\nG28\r\n\nG1 X1 ; go".as_bytes(), true).unwrap();
+        assert_eq!(commands, ["G28", "G1 X1 ; go"]);
+        assert!(read_commands("G28\nG5\n".as_bytes(), true).is_err());
+    }
+
+    #[test]
+    fn strip_comments() {
+        assert_eq!(strip_line_comment("G0 X1 ; go"), "G0 X1");
+        assert_eq!(strip_line_comment("G0 X1 (a;b)"), "G0 X1 (a;b)");
+        assert_eq!(strip_line_comment("G0 X1"), "G0 X1");
+    }
+
+    #[test]
+    fn m400_waits() {
+        assert!(waits_for_motion("M400"));
+        assert!(waits_for_motion("m400 ; sync\r\n"));
+        assert!(!waits_for_motion("M4000"));
+        assert!(!waits_for_motion("G28"));
+    }
+}
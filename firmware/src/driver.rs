@@ -1,9 +1,12 @@
 //! Ref: https://www.allegromicro.com/-/media/files/datasheets/a4988-datasheet.pdf
 
+use core::iter::Peekable;
+
 use defmt::{debug, info, Format};
-use embassy_futures::join::{join, join3};
+use embassy_futures::{join::join3, poll_once};
 use embassy_rp::{
     gpio::{self, Level, Pull},
+    pac,
     pio::{self, PioPin},
     pio_programs::clock_divider::calculate_pio_clock_divider,
     Peri,
@@ -14,10 +17,13 @@ const PIO_TARGET_HZ: u32 =
     // 2 μs per cycle
     500_000;
 
+/// Moves are timed in cycles of the PIO clock
+pub const TICKS_PER_SECOND: u64 = PIO_TARGET_HZ as u64;
+
 #[derive(Debug, Format, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct StepsPerSecond(pub u32);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Direction {
     Forwards,
     Backwards,
@@ -49,6 +55,55 @@ impl StepsPerSecond {
     }
 }
 
+/// Cycles steps.s takes per step, on top of the sleep
+const STEP_CYCLES: u64 = 8;
+/// The most cycles steps.s can sleep for between steps, in the 20 bits it has for them
+const MAX_SLEEP_CYCLES: u64 = (1 << 20) - 1;
+/// The longest one step can take: any longer a gap between steps has to be split up with steps that
+/// don't step any axes
+pub const MAX_STEP_TICKS: u64 = MAX_SLEEP_CYCLES + STEP_CYCLES;
+
+/// One step of a move, on the timebase every axis shares
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Which axes step, and which way. The directions of the axes that don't step are still
+    /// written, so they might as well stay the same for the whole move
+    pub steps: [bool; 3],
+    pub directions: [Direction; 3],
+    /// How long until the next step, in [`TICKS_PER_SECOND`]
+    pub ticks: u64,
+}
+
+/// Where each axis's pins are among the out pins of steps.s, as a mask of the six bits it writes.
+/// The masks count from the lowest pin, so the step and direction pins have to be six consecutive
+/// pins, in any order
+struct StepPins {
+    steps: [u32; 3],
+    directions: [u32; 3],
+}
+
+impl StepPins {
+    /// The word steps.s takes for a step: see steps.s
+    fn word(&self, step: &Step) -> u32 {
+        let mut directions = 0;
+        let mut steps = 0;
+        for axis in 0..3 {
+            if step.directions[axis] == Direction::Forwards {
+                directions |= self.directions[axis];
+            }
+            if step.steps[axis] {
+                steps |= self.steps[axis];
+            }
+        }
+        // A sleep of 0 ends the move, so every step sleeps for at least a cycle
+        let sleep = step
+            .ticks
+            .saturating_sub(STEP_CYCLES)
+            .clamp(1, MAX_SLEEP_CYCLES) as u32;
+        directions | (directions | steps) << 6 | sleep << 12
+    }
+}
+
 pub struct Programs<'a, T: pio::Instance> {
     home: pio::LoadedProgram<'a, T>,
     steps: pio::LoadedProgram<'a, T>,
@@ -165,7 +220,7 @@ impl<'d, T: pio::Instance, const SM: usize> Axis<'d, T, SM> {
 
     pub(self) async fn push_speed(&mut self, speed: StepsPerSecond, direction: Direction) {
         let speed = speed.to_sleep_cyles_per_step();
-        // steps.s expects the direction to be the LSB of speed - if direction is negative,
+        // home.s expects the direction to be the LSB of speed - if direction is negative,
         // pin is low, if positive pin is high
         let speed_and_dir = (speed << 1)
             | (match direction {
@@ -174,6 +229,32 @@ impl<'d, T: pio::Instance, const SM: usize> Axis<'d, T, SM> {
             });
         self.sm.tx().wait_push(speed_and_dir).await;
     }
+
+    /// Push as many steps as there's room for in the FIFO without waiting
+    fn fill(&mut self, pins: &StepPins, steps: &mut Peekable<impl Iterator<Item = Step>>) {
+        while let Some(step) = steps.peek() {
+            if !self.sm.tx().try_push(pins.word(step)) {
+                break;
+            }
+            steps.next();
+        }
+    }
+
+    /// Push the rest of the steps as the state machine makes room for them. A step is only taken
+    /// from `steps` once it's been pushed, so this can be cancelled at any point
+    async fn feed(&mut self, pins: &StepPins, steps: &mut Peekable<impl Iterator<Item = Step>>) {
+        while let Some(step) = steps.peek() {
+            self.sm.tx().wait_push(pins.word(step)).await;
+            steps.next();
+        }
+    }
+
+    /// Wait for the state machine to signal that it's finished, if it was started
+    async fn wait_if(&mut self, started: bool) {
+        if started {
+            self.irq.wait().await;
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -182,11 +263,29 @@ enum ConfiguredProgram {
     Steps,
 }
 
+/// The zero limit switch pin of each axis, if it has one. They can be read from either core, without
+/// waiting for the driver to finish what it's doing
+#[derive(Debug, Clone, Copy)]
+pub struct Endstops([Option<u8>; 3]);
+
+impl Endstops {
+    /// Whether each axis's zero limit switch is triggered, or `None` for axes without one. A
+    /// triggered switch reads high, which is what home.s waits for
+    pub fn read(&self) -> [Option<bool>; 3] {
+        // The pins belong to the PIO, but their inputs can still be read through the SIO
+        let levels = pac::SIO.gpio_in(0).read();
+        self.0.map(|pin| pin.map(|pin| levels & (1 << pin) != 0))
+    }
+}
+
 pub struct Driver<'d, T: pio::Instance, const XSM: usize, const ZSM: usize, const CSM: usize> {
     pio: pio::Common<'d, T>,
     sleep_pin: gpio::Output<'d>,
     axes: (Axis<'d, T, XSM>, Axis<'d, T, ZSM>, Axis<'d, T, CSM>),
     configured_program: Option<ConfiguredProgram>,
+    step_pins: StepPins,
+    /// Whether the axes have been started on a move, and not stopped since
+    moving: bool,
     programs: Programs<'d, T>,
     clock_divider: fixed::FixedU32<U8>,
 }
@@ -236,6 +335,28 @@ impl<'d, T: pio::Instance, const XSM: usize, const ZSM: usize, const CSM: usize>
             Axis::new(&mut pio, axes.c_axis),
         );
 
+        let step_pins = {
+            let pins = Self::step_pins(&axes);
+            let base = pins[0].pin();
+            assert!(
+                pins.iter().zip(base..).all(|(pin, n)| pin.pin() == n),
+                "the step and direction pins have to be six consecutive pins"
+            );
+            let mask = |pin: &pio::Pin<'d, T>| 1 << (pin.pin() - base);
+            StepPins {
+                steps: [
+                    mask(&axes.0.step_pin),
+                    mask(&axes.1.step_pin),
+                    mask(&axes.2.step_pin),
+                ],
+                directions: [
+                    mask(&axes.0.dir_pin),
+                    mask(&axes.1.dir_pin),
+                    mask(&axes.2.dir_pin),
+                ],
+            }
+        };
+
         let sleep_pin = gpio::Output::new(sleep_pin, Level::Low);
 
         Self {
@@ -243,24 +364,58 @@ impl<'d, T: pio::Instance, const XSM: usize, const ZSM: usize, const CSM: usize>
             sleep_pin,
             axes,
             configured_program: None,
+            step_pins,
+            moving: false,
             clock_divider,
             programs,
         }
     }
 
+    /// Every axis's step and direction pins, in order. steps.s writes all of them at once, so they
+    /// have to be consecutive
+    fn step_pins<'a>(
+        axes: &'a (Axis<'d, T, XSM>, Axis<'d, T, ZSM>, Axis<'d, T, CSM>),
+    ) -> [&'a pio::Pin<'d, T>; 6] {
+        let mut pins = [
+            &axes.0.step_pin,
+            &axes.0.dir_pin,
+            &axes.1.step_pin,
+            &axes.1.dir_pin,
+            &axes.2.step_pin,
+            &axes.2.dir_pin,
+        ];
+        pins.sort_unstable_by_key(|pin| pin.pin());
+        pins
+    }
+
     fn configure_pio(&mut self, which_program: ConfiguredProgram) {
         if self.configured_program == Some(which_program) {
             return;
         }
 
-        let program = match which_program {
-            ConfiguredProgram::Home => &self.programs.home,
-            ConfiguredProgram::Steps => &self.programs.steps,
-        };
-
-        each_axis!(self, |_i, axis| {
-            axis.configure(self.clock_divider, program);
-        });
+        match which_program {
+            ConfiguredProgram::Home => {
+                each_axis!(self, |_i, axis| {
+                    axis.configure(self.clock_divider, &self.programs.home);
+                });
+            }
+            // steps.s runs every axis from the first axis's state machine, so they all step on the
+            // same clock
+            ConfiguredProgram::Steps => {
+                let mut cfg = pio::Config::default();
+                cfg.set_out_pins(&Self::step_pins(&self.axes));
+                cfg.shift_out = pio::ShiftConfig {
+                    threshold: 32,
+                    direction: pio::ShiftDirection::Right,
+                    auto_fill: true,
+                };
+                // steps.s only ever takes input, so give it room to queue up more steps
+                cfg.fifo_join = pio::FifoJoin::TxOnly;
+                cfg.clock_divider = self.clock_divider;
+                cfg.use_program(&self.programs.steps, &[]);
+                self.axes.0.sm.set_config(&cfg);
+            }
+        }
 
         self.configured_program = Some(which_program);
     }
@@ -270,69 +425,109 @@ impl<'d, T: pio::Instance, const XSM: usize, const ZSM: usize, const CSM: usize>
             .set_level(if sleep { Level::Low } else { Level::High });
     }
 
-    pub async fn home(&mut self, speeds: impl IntoIterator<Item = StepsPerSecond>) {
+    /// The zero limit switches, to read from elsewhere
+    pub fn endstops(&self) -> Endstops {
+        let pin = |pin: &Option<pio::Pin<'d, T>>| pin.as_ref().map(|pin| pin.pin());
+        Endstops([
+            pin(&self.axes.0.zero_limit_pin),
+            pin(&self.axes.1.zero_limit_pin),
+            pin(&self.axes.2.zero_limit_pin),
+        ])
+    }
+
+    /// Stop every axis where it is, even part way through a move, and put the steppers to sleep
+    pub async fn emergency_stop(&mut self) {
+        self.set_sleep(true).await;
+        self.pio.apply_sm_batch(|batch| {
+            each_axis!(self, |_, axis| {
+                batch.set_enable(&mut axis.sm, false);
+            });
+        });
+        each_axis!(self, |_, axis| {
+            axis.sm.clear_fifos();
+            axis.sm.set_pins(Level::Low, &[&axis.step_pin]);
+            // An axis that finished before the others were stopped has left its irq set, which
+            // would end the next move early
+            let _ = poll_once(axis.irq.wait());
+        });
+        // Make the next move start its program from the beginning
+        self.configured_program = None;
+        self.moving = false;
+    }
+
+    /// Home the axes with a speed given, backwards until they hit their zero limit. Axes without a
+    /// zero limit can't be homed, and are left where they are
+    pub async fn home(&mut self, speeds: [Option<StepsPerSecond>; 3]) {
         debug!("homing");
         self.configure_pio(ConfiguredProgram::Home);
 
-        let mut speeds = speeds.into_iter();
-
+        let mut homing = [false; 3];
         each_axis!(self, |i, axis| {
-            if let Some(speed) = speeds.next() {
+            if let Some(speed) = speeds[i] {
                 if axis.zero_limit_pin.is_some() {
                     info!("will home axis {}", i);
                     axis.push_speed(speed, Direction::Backwards).await;
+                    homing[i] = true;
                 }
             }
         });
 
         debug!("starting home routine");
         self.pio.apply_sm_batch(|batch| {
-            each_axis!(self, |_, axis| {
-                if axis.zero_limit_pin.is_some() {
+            each_axis!(self, |i, axis| {
+                if homing[i] {
                     batch.restart(&mut axis.sm);
                     batch.set_enable(&mut axis.sm, true);
                 }
             });
         });
 
-        join(self.axes.0.irq.wait(), self.axes.1.irq.wait()).await;
+        join3(
+            self.axes.0.wait_if(homing[0]),
+            self.axes.1.wait_if(homing[1]),
+            self.axes.2.wait_if(homing[2]),
+        )
+        .await;
         debug!("finished home routine");
     }
 
-    pub async fn do_move(&mut self, steps: [i32; 3], speeds: [StepsPerSecond; 3]) {
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    /// Stream the steps of a move to steps.s as it makes room for them, starting it if it's
+    /// stopped. This only returns once every step has been queued, but it can be cancelled and
+    /// picked up again with the same steps at any point. Once there are no more moves to carry on
+    /// with, [`Self::finish`] stops the axes
+    pub async fn stream(&mut self, steps: &mut Peekable<impl Iterator<Item = Step>>) {
         self.configure_pio(ConfiguredProgram::Steps);
 
-        each_axis!(self, |i, axis| {
-            // corresponds to [pull block] instructions in steps.s
-            axis.sm.tx().wait_push(steps[i].unsigned_abs()).await;
-            axis.push_speed(speeds[i], Direction::from(steps[i])).await;
-        });
+        if !self.moving {
+            // Queue up the start of the move before starting, so it doesn't run out of steps
+            // straight away
+            self.axes.0.fill(&self.step_pins, steps);
+            self.axes.0.sm.restart();
+            self.axes.0.sm.set_enable(true);
+            self.moving = true;
+        }
 
-        self.pio.apply_sm_batch(|batch| {
-            each_axis!(self, |_, axis| {
-                batch.restart(&mut axis.sm);
-                batch.set_enable(&mut axis.sm, true);
-            });
-        });
+        self.axes.0.feed(&self.step_pins, steps).await;
+    }
 
-        info!("waiting on irqs");
-        join3(
-            self.axes.0.irq.wait(),
-            self.axes.1.irq.wait(),
-            self.axes.2.irq.wait(),
-        )
-        .await;
+    /// Wait for the axes to finish every step streamed to them, and stop them
+    pub async fn finish(&mut self) {
+        if !self.moving {
+            return;
+        }
+        // A step with no sleep ends the move
+        self.axes.0.sm.tx().wait_push(0).await;
+
+        info!("waiting on irq");
+        self.axes.0.irq.wait().await;
         info!("done");
 
-        self.pio.apply_sm_batch(|batch| {
-            each_axis!(self, |_, axis| {
-                batch.set_enable(&mut axis.sm, false);
-            });
-        });
-        self.pio.apply_sm_batch(|batch| {
-            each_axis!(self, |_, axis| {
-                batch.restart(&mut axis.sm);
-            });
-        });
+        self.axes.0.sm.set_enable(false);
+        self.axes.0.sm.restart();
+        self.moving = false;
     }
 }
@@ -0,0 +1,155 @@
+//! Coordinated stepping. The leading axis, the one with the most steps to take, steps to the move's
+//! [`Profile`], and the other axes step along with it, Bresenham style: each step of the leading
+//! axis, every other axis steps if it's fallen behind its share of the move by half a step or more.
+//! Every step is on the same clock, so the axes start and end the move together, and never get more
+//! than half a step out of line in between
+
+use crate::{
+    driver::{Direction, Step, MAX_STEP_TICKS},
+    ramp::{Profile, Segment, Segments},
+    AXES,
+};
+
+/// An iterator over the steps of a move, for [`crate::driver::Driver::stream`]
+pub struct Steps {
+    segments: Segments,
+    /// The segment being stepped through, and how many of its steps are left to take
+    segment: Segment,
+    left: u32,
+    /// The steps each axis takes over the whole move
+    steps: [u32; AXES],
+    directions: [Direction; AXES],
+    leading: u32,
+    /// How far each axis has gotten through its next step, out of `leading`
+    errors: [u64; AXES],
+    /// How long is left to wait before the next step, once the longest step steps.s can do is up
+    wait_ticks: u64,
+}
+
+impl Steps {
+    pub fn new(profile: Profile, steps: [i32; AXES]) -> Self {
+        let leading = steps
+            .iter()
+            .map(|steps| steps.unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        Self {
+            segments: profile.segments(leading),
+            segment: Segment { steps: 0, ticks: 0 },
+            left: 0,
+            steps: steps.map(i32::unsigned_abs),
+            directions: steps.map(Direction::from),
+            leading,
+            // Starting half way rounds each axis's steps to the nearest step of the leading axis
+            errors: [u64::from(leading / 2); AXES],
+            wait_ticks: 0,
+        }
+    }
+
+    fn step(&self, steps: [bool; AXES], ticks: u64) -> Step {
+        Step {
+            steps,
+            directions: self.directions,
+            ticks,
+        }
+    }
+}
+
+impl Iterator for Steps {
+    type Item = Step;
+
+    fn next(&mut self) -> Option<Step> {
+        if self.wait_ticks > 0 {
+            let ticks = self.wait_ticks.min(MAX_STEP_TICKS);
+            self.wait_ticks -= ticks;
+            return Some(self.step([false; AXES], ticks));
+        }
+
+        while self.left == 0 {
+            self.segment = self.segments.next()?;
+            // A segment with no steps waits all in one go
+            self.left = self.segment.steps.max(1);
+        }
+        // Space the segment's steps out as evenly as whole ticks allow, keeping its total exact
+        let steps = self.segment.steps.max(1);
+        let at = |taken: u32| {
+            (u128::from(self.segment.ticks) * u128::from(taken) / u128::from(steps)) as u64
+        };
+        let taken = steps - self.left;
+        let ticks = at(taken + 1) - at(taken);
+        self.left -= 1;
+
+        let mut stepping = [false; AXES];
+        if self.segment.steps > 0 {
+            for (axis, stepping) in stepping.iter_mut().enumerate() {
+                self.errors[axis] += u64::from(self.steps[axis]);
+                if self.errors[axis] >= u64::from(self.leading) {
+                    self.errors[axis] -= u64::from(self.leading);
+                    *stepping = true;
+                }
+            }
+        }
+
+        let step_ticks = ticks.min(MAX_STEP_TICKS);
+        self.wait_ticks = ticks - step_ticks;
+        Some(self.step(stepping, step_ticks))
+    }
+}
+
+#[cfg(test)]
+#[embedded_test::tests]
+mod tests {
+    use super::*;
+    use crate::driver::TICKS_PER_SECOND;
+
+    const SECOND: u64 = TICKS_PER_SECOND;
+
+    #[test]
+    fn axes_start_and_end_together() {
+        let profile = Profile::new(SECOND, SECOND / 5, 0, 0);
+        let moves = [[0, 0, 0], [1000, 0, 0], [-7, 300, 1000], [999, -1000, 1]];
+        for steps in moves {
+            let mut taken = [0; AXES];
+            let mut ticks = 0;
+            for step in Steps::new(profile, steps) {
+                for axis in 0..AXES {
+                    taken[axis] += u32::from(step.steps[axis]);
+                }
+                ticks += step.ticks;
+            }
+            assert_eq!(taken, steps.map(i32::unsigned_abs));
+            assert_eq!(ticks, profile.total_ticks());
+        }
+    }
+
+    #[test]
+    fn axes_stay_in_line() {
+        let profile = Profile::new(SECOND, SECOND / 5, 0, 0);
+        let steps = [-7, 300, 1000];
+        let mut taken = [0; AXES];
+        for step in Steps::new(profile, steps) {
+            for axis in 0..AXES {
+                taken[axis] += i64::from(step.steps[axis]);
+            }
+            for axis in 0..AXES {
+                // Where the axis should be, given how far the leading axis has gotten, out of
+                // the leading axis's steps
+                let should_be = i64::from(steps[axis].unsigned_abs()) * taken[2];
+                assert!((taken[axis] * 1000 - should_be).abs() <= 1000 / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn waits_out_long_steps() {
+        // One step every ten seconds
+        let profile = Profile::new(10 * SECOND, 1, 0, 0);
+        let steps = heapless::Vec::<_, 16>::from_iter(Steps::new(profile, [1, 0, 0]));
+        assert!(steps.iter().all(|step| step.ticks <= MAX_STEP_TICKS));
+        assert_eq!(steps.iter().filter(|step| step.steps[0]).count(), 1);
+        assert_eq!(
+            steps.iter().map(|step| step.ticks).sum::<u64>(),
+            profile.total_ticks()
+        );
+    }
+}
@@ -18,7 +18,7 @@ use embassy_rp::{
     pio::{InterruptHandler, Pio},
     Peri,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel, signal::Signal};
 use embassy_time::{Duration, Timer};
 use heapless::Vec;
 use picoserve::make_static;
@@ -29,25 +29,92 @@ use crate::motion::ICoord;
 use {defmt_rtt as _, panic_probe as _};
 
 mod driver;
+mod interpolate;
 mod motion;
+mod planner;
+mod program;
+mod ramp;
 mod server;
 pub(crate) mod util;
 
 pub(crate) const WIFI_NETWORK: Option<&str> = option_env!("WIFI_NETWORK");
 pub(crate) const WIFI_PASSWORD: Option<&str> = option_env!("WIFI_PASSWORD");
 pub(crate) const PORT: u16 = 1234;
-pub(crate) const AXES: usize = 4;
-pub(crate) const AXIS_LABELS: [char; AXES] = ['X', 'Z', 'C', 'F'];
+pub(crate) const AXES: usize = 3;
+pub(crate) const AXIS_LABELS: [char; AXES] = ['X', 'Z', 'C'];
 pub const COMMAND_BUFFER_SIZE: usize = 32;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
 pub struct CommandId(pub u32);
 
+/// The current position of the machine, in the active units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub coords: [ICoord; AXES],
+    pub feedrate: gcode::UCoord,
+    pub units: gcode::Units,
+}
+
+/// Short name for the units, as reported to clients
+pub fn units_name(units: gcode::Units) -> &'static str {
+    match units {
+        gcode::Units::Inches => "in",
+        gcode::Units::Millimeters => "mm",
+    }
+}
+
+impl Position {
+    pub fn units_name(&self) -> &'static str {
+        units_name(self.units)
+    }
+}
+
+impl Format for Position {
+    fn format(&self, fmt: Formatter) {
+        for (label, coord) in AXIS_LABELS.iter().zip(self.coords) {
+            defmt::write!(fmt, "{}{} ", label, Display2Format(&coord));
+        }
+        defmt::write!(
+            fmt,
+            "F{} ({})",
+            Display2Format(&self.feedrate),
+            self.units_name()
+        );
+    }
+}
+
+/// The calibration of each axis, in steps per unit (of the active units, for linear axes) or per
+/// rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepsPerUnit {
+    pub steps: [ICoord; AXES],
+    pub units: gcode::Units,
+}
+
+impl Format for StepsPerUnit {
+    fn format(&self, fmt: Formatter) {
+        for (label, steps) in AXIS_LABELS.iter().zip(self.steps) {
+            defmt::write!(fmt, "{}{} ", label, Display2Format(&steps));
+        }
+        defmt::write!(fmt, "steps per {}", units_name(self.units));
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
 pub enum MotionStatusMsg {
     CommandFinished(CommandId),
+    /// Reported in place of [`MotionStatusMsg::CommandFinished`] for a command that couldn't be
+    /// carried out, or on its own for one run by a subroutine call or loop, which has no id
+    CommandFailed(Option<CommandId>, &'static str),
+    /// Reported in response to M114, before the command finishes
+    Position(Position),
+    /// Reported in response to M92 without any axes
+    StepsPerUnit(StepsPerUnit),
 }
 
+/// Raised by the server on M112, to stop the motion task dead
+static EMERGENCY_STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
 });
@@ -76,7 +143,7 @@ async fn motion_task(
     command_rx: channel::Receiver<
         'static,
         CriticalSectionRawMutex,
-        (CommandId, gcode::Command<AXES>),
+        (Option<CommandId>, gcode::Command<AXES>),
         COMMAND_BUFFER_SIZE,
     >,
     status_tx: channel::Sender<
@@ -86,7 +153,9 @@ async fn motion_task(
         COMMAND_BUFFER_SIZE,
     >,
 ) -> ! {
-    motion.run(driver, command_rx, status_tx).await;
+    motion
+        .run(driver, command_rx, status_tx, &EMERGENCY_STOP)
+        .await;
 }
 
 async fn blink_once(control: &mut Control<'_>) {
@@ -104,7 +173,7 @@ async fn core0(
     command_tx: channel::Sender<
         'static,
         CriticalSectionRawMutex,
-        (CommandId, gcode::Command<AXES>),
+        (Option<CommandId>, gcode::Command<AXES>),
         COMMAND_BUFFER_SIZE,
     >,
     status_rx: channel::Receiver<
@@ -113,6 +182,7 @@ async fn core0(
         MotionStatusMsg,
         COMMAND_BUFFER_SIZE,
     >,
+    endstops: driver::Endstops,
 ) {
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
@@ -156,7 +226,10 @@ async fn core0(
         control,
         command_tx,
         status_rx,
+        emergency_stop: &EMERGENCY_STOP,
+        endstops,
         command_id_gen: 0,
+        alarm: false,
     }));
 }
 
@@ -215,7 +288,7 @@ fn main() -> ! {
     static COMMAND_CHANNEL: StaticCell<
         channel::Channel<
             CriticalSectionRawMutex,
-            (CommandId, gcode::Command<AXES>),
+            (Option<CommandId>, gcode::Command<AXES>),
             COMMAND_BUFFER_SIZE,
         >,
     > = StaticCell::new();
@@ -230,6 +303,7 @@ fn main() -> ! {
     let status_tx = status_channel.sender();
     let status_rx = status_channel.receiver();
 
+    let endstops = driver.endstops();
     embassy_rp::multicore::spawn_core1(
         p.CORE1,
         unsafe { &mut *addr_of_mut!(CORE1_STACK) },
@@ -237,26 +311,20 @@ fn main() -> ! {
             let executor1 = EXECUTOR1.init(Executor::new());
             executor1.run(|spawner| {
                 spawner.must_spawn(motion_task(
-                    motion::State::new([
-                        /* X */
-                        motion::Axis {
-                            microns_per_step: ICoord::from_num(12).into(),
-                            degrees_per_step: (ICoord::lit("1.8") / ICoord::from_num(16)).into(),
-                            unit: motion::AxisUnit::Millimeters,
-                        },
-                        /* Z */
-                        motion::Axis {
-                            microns_per_step: (ICoord::from_num(6)).into(),
-                            degrees_per_step: (ICoord::lit("0.9") / ICoord::from_num(16)).into(),
-                            unit: motion::AxisUnit::Millimeters,
-                        },
-                        /* C */
-                        motion::Axis {
-                            microns_per_step: ICoord::from_num(12).into(),
-                            degrees_per_step: (ICoord::lit("1.8") / ICoord::from_num(16)).into(),
-                            unit: motion::AxisUnit::Rotations,
-                        },
-                    ]),
+                    motion::State::new(
+                        [
+                            /* X */
+                            motion::Axis::Linear(ICoord::from_num(12).into()),
+                            /* Z */
+                            motion::Axis::Linear(ICoord::from_num(6).into()),
+                            /* C */
+                            // 1.8° steps, microstepped 16 times
+                            motion::Axis::Rotational(ICoord::from_num(200 * 16).into()),
+                        ],
+                        // Back at the endstops, clear of the bobbin, until G27.1 sets it
+                        /* park_position = */
+                        [Some(ICoord::ZERO), Some(ICoord::ZERO), None],
+                    ),
                     driver,
                     command_rx,
                     status_tx,
@@ -266,5 +334,7 @@ fn main() -> ! {
     );
 
     let executor0 = EXECUTOR0.init(Executor::new());
-    executor0.run(|spawner| spawner.must_spawn(core0(pwr, spi, spawner, command_tx, status_rx)))
+    executor0.run(|spawner| {
+        spawner.must_spawn(core0(pwr, spi, spawner, command_tx, status_rx, endstops))
+    })
 }
@@ -1,22 +1,27 @@
+use core::{array, future, iter::Peekable};
+
 use az::SaturatingCast;
-use defmt::{info, Display2Format, Format};
+use defmt::{info, warn, Display2Format, Format};
+use embassy_futures::select::{select, Either};
 use embassy_rp::pio;
 use embassy_sync::{
     blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex},
     channel,
+    signal::Signal,
 };
 use embassy_time::Timer;
-use fixed::{types::extra::U10, FixedI32};
 use fixed_sqrt::FastSqrt;
-use gcode::{Command, UCoord};
+use gcode::{Command, DistanceMode, Pos, UCoord, Units};
 
 use crate::{
-    driver::{self, StepsPerSecond},
+    driver::{self, StepsPerSecond, TICKS_PER_SECOND},
+    interpolate::Steps,
+    planner::{Planned, PlannedMove, Planner},
     util::ArrayZipWith,
-    CommandId, MotionStatusMsg, COMMAND_BUFFER_SIZE,
+    CommandId, MotionStatusMsg, Position, StepsPerUnit, COMMAND_BUFFER_SIZE,
 };
 
-pub type ICoord = FixedI32<U10>;
+pub use gcode::ICoord;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MicronsPerStep(pub ICoord);
@@ -27,35 +32,68 @@ impl From<ICoord> for MicronsPerStep {
     }
 }
 
+/// Kept as steps rather than degrees, since a step's angle is rarely a round number in binary
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct DegreesPerStep(pub ICoord);
+pub struct StepsPerRotation(pub ICoord);
 
-impl From<ICoord> for DegreesPerStep {
+impl From<ICoord> for StepsPerRotation {
     fn from(value: ICoord) -> Self {
         Self(value)
     }
 }
 
+/// What an axis moves in, and how far it moves each step
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum AxisUnit {
-    Millimeters,
-    Rotations,
+pub enum Axis {
+    /// Moves in millimeters
+    Linear(MicronsPerStep),
+    /// Moves in rotations
+    Rotational(StepsPerRotation),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Axis {
-    pub microns_per_step: MicronsPerStep,
-    pub degrees_per_step: DegreesPerStep,
-    pub unit: AxisUnit,
-}
+impl Axis {
+    /// Steps per millimeter, or per rotation for rotational axes
+    fn steps_per_unit(&self) -> ICoord {
+        match self {
+            Axis::Linear(MicronsPerStep(microns)) => {
+                ICoord::from_num(1000).saturating_div(*microns)
+            }
+            Axis::Rotational(StepsPerRotation(steps)) => *steps,
+        }
+    }
 
-fn diff(coord1: UCoord, coord2: UCoord) -> ICoord {
-    if coord1 > coord2 {
-        (coord1 - coord2).saturating_cast()
-    } else {
-        let abs_diff: ICoord = (coord2 - coord1).saturating_cast();
-        abs_diff.saturating_neg()
+    /// How many steps it takes to go the given distance (in millimeters or rotations)
+    fn steps(&self, dist: ICoord) -> i32 {
+        match self {
+            Axis::Linear(MicronsPerStep(microns_per_step)) => {
+                let microns = dist.saturating_mul(ICoord::from_num(1000));
+                (microns / *microns_per_step).saturating_cast()
+            }
+            // Multiplied out wide, since a coil can take more steps than an ICoord can count
+            Axis::Rotational(StepsPerRotation(steps)) => dist.wide_mul(*steps).saturating_cast(),
+        }
+    }
+
+    /// Convert a speed in millimeters (or rotations) per second
+    fn steps_per_second(&self, speed: UCoord) -> StepsPerSecond {
+        StepsPerSecond(
+            speed
+                .saturating_mul(self.steps_per_unit().unsigned_abs())
+                .saturating_to_num(),
+        )
     }
+
+    /// Recalibrate from the steps per millimeter, or per rotation for rotational axes
+    fn set_steps_per_unit(&mut self, steps: ICoord) {
+        *self = match self {
+            Axis::Linear(_) => Axis::Linear(ICoord::from_num(1000).saturating_div(steps).into()),
+            Axis::Rotational(_) => Axis::Rotational(steps.into()),
+        }
+    }
+}
+
+fn diff(coord1: ICoord, coord2: ICoord) -> ICoord {
+    coord1.saturating_sub(coord2)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -67,205 +105,663 @@ impl Format for MillimetersPerSecond {
     }
 }
 
-impl MillimetersPerSecond {
-    fn to_steps_per_second(
-        self,
-        MicronsPerStep(microns_per_step): MicronsPerStep,
-    ) -> StepsPerSecond {
-        let microns = self.0 * UCoord::from_num(1000);
-        let steps = microns / microns_per_step.unsigned_abs();
-        StepsPerSecond(steps.saturating_cast())
-    }
-}
+const MILLIMETERS_PER_INCH: ICoord = ICoord::lit("25.4");
 
 const HOME_SPEED: MillimetersPerSecond = MillimetersPerSecond(UCoord::lit("120"));
 
+const DEFAULT_ACCELERATION: UCoord = UCoord::lit("100");
+
+/// The most any axis's speed can change all at once at the junction between two moves, in
+/// millimeters (or rotations) per second
+const MAX_SPEED_JUMP: UCoord = UCoord::lit("0.25");
+
 const AXES: usize = 3;
 
+type StatusTx =
+    channel::Sender<'static, CriticalSectionRawMutex, MotionStatusMsg, COMMAND_BUFFER_SIZE>;
+
+/// The move being streamed to the driver
+struct Streaming {
+    steps: Peekable<Steps>,
+    /// Whether it comes to a stop at its end
+    stops: bool,
+}
+
 pub struct State {
-    is_homed: bool,
+    is_homed: [bool; AXES],
     /// Feedrate is always in terms of the C axis
     feedrate: MillimetersPerSecond,
-    position: [UCoord; AXES],
+    distance_mode: DistanceMode,
+    /// Units of the coordinates and feedrates in commands. Internally, linear axes are always in
+    /// millimeters
+    units: Units,
+    position: [ICoord; AXES],
     axes: [Axis; AXES],
+    /// Where to move to for G27, if no position is given. Set with G27.1
+    park_position: [Option<ICoord>; AXES],
+    /// How fast each axis can go, in millimeters (or rotations) per second
+    max_feedrate: [Option<UCoord>; AXES],
+    /// How quickly each axis can accelerate, in millimeters (or rotations) per second squared
+    max_acceleration: [Option<UCoord>; AXES],
+    /// How quickly moves accelerate, in millimeters per second squared. Like the feedrate, this is
+    /// in terms of the C axis
+    acceleration: UCoord,
+    /// Moves waiting to be run, and commands waiting on them
+    planner: Planner,
+    streaming: Option<Streaming>,
 }
 
 impl State {
-    pub fn new(axes: [Axis; AXES]) -> Self {
+    pub fn new(axes: [Axis; AXES], park_position: [Option<ICoord>; AXES]) -> Self {
         Self {
-            is_homed: false,
+            is_homed: [false; AXES],
             feedrate: MillimetersPerSecond(UCoord::lit("1")),
-            position: [UCoord::ZERO; AXES],
+            distance_mode: DistanceMode::Absolute,
+            units: Units::Millimeters,
+            position: [ICoord::ZERO; AXES],
             axes,
+            park_position,
+            max_feedrate: [None; AXES],
+            max_acceleration: [None; AXES],
+            acceleration: DEFAULT_ACCELERATION,
+            planner: Planner::new(),
+            streaming: None,
         }
     }
 
-    pub async fn run<const XSM: usize, const CSM: usize, const ZSM: usize>(
-        mut self,
-        mut driver: driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
-        command_rx: channel::Receiver<
-            'static,
-            impl RawMutex,
-            (CommandId, Command<{ AXES + 1 } /* for F */>),
-            COMMAND_BUFFER_SIZE,
-        >,
-        status_tx: channel::Sender<
-            'static,
-            CriticalSectionRawMutex,
-            MotionStatusMsg,
-            COMMAND_BUFFER_SIZE,
-        >,
-    ) -> ! {
+    /// Whether coordinates on the given axis are in inches, and need converting to millimeters
+    fn is_in_inches(&self, axis: usize) -> bool {
+        matches!(self.axes[axis], Axis::Linear(_)) && self.units == Units::Inches
+    }
+
+    /// Convert a coordinate on the given axis from the active units
+    fn coord_from_units(&self, axis: usize, coord: ICoord) -> ICoord {
+        if self.is_in_inches(axis) {
+            coord.saturating_mul(MILLIMETERS_PER_INCH)
+        } else {
+            coord
+        }
+    }
+
+    /// Convert a coordinate on the given axis to the active units
+    fn coord_to_units(&self, axis: usize, coord: ICoord) -> ICoord {
+        if self.is_in_inches(axis) {
+            coord / MILLIMETERS_PER_INCH
+        } else {
+            coord
+        }
+    }
+
+    fn feedrate_from_units(&self, feedrate: UCoord) -> MillimetersPerSecond {
+        match self.units {
+            Units::Inches => {
+                MillimetersPerSecond(feedrate.saturating_mul(MILLIMETERS_PER_INCH.unsigned_abs()))
+            }
+            Units::Millimeters => MillimetersPerSecond(feedrate),
+        }
+    }
+
+    fn feedrate_to_units(&self, MillimetersPerSecond(feedrate): MillimetersPerSecond) -> UCoord {
+        match self.units {
+            Units::Inches => feedrate / MILLIMETERS_PER_INCH.unsigned_abs(),
+            Units::Millimeters => feedrate,
+        }
+    }
+
+    fn max_steps_per_second(&self, axis: usize) -> Option<StepsPerSecond> {
+        self.max_feedrate[axis].map(|max| self.axes[axis].steps_per_second(max))
+    }
+
+    /// Slow every axis down by the same factor, so that none of them go faster than their max
+    /// feedrate. Slowing them all down together keeps the move in a straight line
+    fn limit_speeds(&self, speeds: [StepsPerSecond; AXES]) -> [StepsPerSecond; AXES] {
+        // The max speed and speed of the axis that's furthest over its limit
+        let mut limiting: Option<(u64, u64)> = None;
+        for (axis, speed) in speeds.iter().enumerate() {
+            let Some(max) = self.max_steps_per_second(axis) else {
+                continue;
+            };
+            let (max, speed) = (u64::from(max.0), u64::from(speed.0));
+            if speed > max
+                && limiting.is_none_or(|(limiting_max, limiting_speed)| {
+                    max * limiting_speed < limiting_max * speed
+                })
+            {
+                limiting = Some((max, speed));
+            }
+        }
+        match limiting {
+            Some((max, speed)) => {
+                speeds.map(|StepsPerSecond(s)| StepsPerSecond((u64::from(s) * max / speed) as u32))
+            }
+            None => speeds,
+        }
+    }
+
+    /// How long it takes to get up to the given speeds from a stop. The leading axis would go at
+    /// `unlimited_speed` at the current feedrate, if it weren't for the max feedrates
+    fn ramp_ticks(
+        &self,
+        speeds: [StepsPerSecond; AXES],
+        leading: usize,
+        unlimited_speed: StepsPerSecond,
+    ) -> u64 {
+        // The acceleration is in terms of the feedrate, so scale it by however much the move was
+        // slowed down
+        let feedrate = u64::from(self.feedrate.0.to_bits()) * u64::from(speeds[leading].0)
+            / u64::from(unlimited_speed.0.max(1));
+        let mut ticks = feedrate * TICKS_PER_SECOND / u64::from(self.acceleration.to_bits().max(1));
+        // Take longer if that's too fast for any of the axes
+        for (axis, speed) in speeds.iter().enumerate() {
+            if let Some(max) = self.max_acceleration[axis] {
+                // Steps per second squared, which converts the same way
+                let max = self.axes[axis].steps_per_second(max);
+                ticks = ticks.max(u64::from(speed.0) * TICKS_PER_SECOND / u64::from(max.0.max(1)));
+            }
+        }
+        ticks
+    }
+
+    /// Plan a move to the given absolute position (in millimeters or rotations), leaving any axes
+    /// without a coordinate where they are
+    async fn move_to<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        status_tx: &StatusTx,
+        target_pos: [Option<ICoord>; AXES],
+    ) {
+        let mut dist = self
+            .position
+            .each_mut()
+            .zip_with(target_pos, |p1, p2| match p2 {
+                Some(target_pos) => {
+                    let res = diff(target_pos, *p1);
+                    // TODO(aspen): Don't update position until after moving, to handle canceled moves
+                    *p1 = target_pos;
+                    res
+                }
+                None => ICoord::ZERO,
+            });
+        dist[2] = dist[2].saturating_neg();
+
+        let (steps, speed) = self.steps_and_speeds(dist);
+
+        let speeds = self.limit_speeds(speed);
+        // Every axis takes as long as the others, so go by the one with the furthest to go
+        let leading = (0..AXES)
+            .max_by_key(|&axis| steps[axis].unsigned_abs())
+            .unwrap_or_default();
+        let ticks = (u64::from(steps[leading].unsigned_abs()) * TICKS_PER_SECOND
+            / u64::from(speeds[leading].0.max(1)))
+        .max(1);
+        let ramp_ticks = self.ramp_ticks(speeds, leading, speed[leading]);
+        let max_jumps = array::from_fn(|axis| self.axes[axis].steps_per_second(MAX_SPEED_JUMP));
+
+        self.make_room(driver, status_tx).await;
+        self.planner.push_move(
+            PlannedMove::new(steps, speeds, ticks, ramp_ticks),
+            max_jumps,
+        );
+    }
+
+    /// The steps each axis takes to go the given distance, and how fast each should go to keep to
+    /// the feedrate
+    fn steps_and_speeds(&self, dist: [ICoord; AXES]) -> ([i32; AXES], [StepsPerSecond; AXES]) {
+        let steps = dist.zip_with(self.axes, |dist, axis| axis.steps(dist));
+
+        let speeds = if steps[2] == 0 {
+            if dist[1].is_zero() {
+                [
+                    self.axes[0].steps_per_second(self.feedrate.0),
+                    StepsPerSecond(0),
+                    StepsPerSecond(0),
+                ]
+            } else if dist[0].is_zero() {
+                [
+                    StepsPerSecond(0),
+                    self.axes[1].steps_per_second(self.feedrate.0),
+                    StepsPerSecond(0),
+                ]
+            } else {
+                // if c isn't moving, base the feedrate calculation on a triangle
+                let x_fr = {
+                    let z_over_x = dist[1]
+                        .unsigned_abs()
+                        .saturating_div(dist[0].unsigned_abs());
+                    self.feedrate.0
+                        / z_over_x
+                            .saturating_mul(z_over_x)
+                            .saturating_add(UCoord::ONE)
+                            .fast_sqrt()
+                };
+                let z_fr = {
+                    let x_over_z = dist[0]
+                        .unsigned_abs()
+                        .saturating_div(dist[1].unsigned_abs());
+                    self.feedrate.0
+                        / x_over_z
+                            .saturating_mul(x_over_z)
+                            .saturating_add(UCoord::ONE)
+                            .fast_sqrt()
+                };
+                [
+                    self.axes[0].steps_per_second(x_fr),
+                    self.axes[1].steps_per_second(z_fr),
+                    StepsPerSecond(0),
+                ]
+            }
+        } else {
+            let c_speed = self.axes[2].steps_per_second(self.feedrate.0);
+            // The move takes as long as the C axis takes to get there (a stopped C axis takes
+            // forever), but never so little time that it rounds down to zero
+            let dur_s = UCoord::saturating_from_num(steps[2].unsigned_abs())
+                .checked_div(UCoord::saturating_from_num(c_speed.0))
+                .unwrap_or(UCoord::MAX)
+                .max(UCoord::DELTA);
+            [
+                StepsPerSecond(
+                    UCoord::saturating_from_num(steps[0].unsigned_abs())
+                        .saturating_div(dur_s)
+                        .saturating_to_num(),
+                ),
+                StepsPerSecond(
+                    UCoord::saturating_from_num(steps[1].unsigned_abs())
+                        .saturating_div(dur_s)
+                        .saturating_to_num(),
+                ),
+                c_speed,
+            ]
+        };
+        (steps, speeds)
+    }
+
+    /// Take the next move off the planner to stream to the driver, reporting any commands that were
+    /// waiting on the ones before it. Returns false if there's nothing left
+    async fn start_next(&mut self, status_tx: &StatusTx) -> bool {
         loop {
-            let (command_id, command) = command_rx.receive().await;
-            info!("got command");
-            match command {
-                Command::Stop => continue,
-                Command::Dwell(duration) => {
-                    Timer::after_millis(duration.as_millis() as _).await;
+            match self.planner.pop() {
+                None => return false,
+                Some(Planned::Done(command_id)) => {
+                    info!("command {} done", command_id);
+                    status_tx
+                        .send(MotionStatusMsg::CommandFinished(command_id))
+                        .await;
                 }
-                Command::EnableAllSteppers => {
-                    info!("enabling steppers");
-                    driver.set_sleep(false).await
+                Some(Planned::Move { steps, profile }) => {
+                    self.streaming = Some(Streaming {
+                        steps: Steps::new(profile, steps).peekable(),
+                        stops: profile.stops(),
+                    });
+                    return true;
                 }
-                Command::DisableAllSteppers => {
-                    info!("disabling steppers");
-                    driver.set_sleep(true).await;
-
-                    // If we disable the motors, we have to assume we don't know where we are
-                    // anymore
-                    self.is_homed = false;
-                    for coord in self.position.each_mut() {
-                        *coord = UCoord::ZERO;
+            }
+        }
+    }
+
+    /// Stream the rest of the move being streamed, if any, stopping at its end if it stops there
+    async fn stream<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+    ) {
+        if let Some(streaming) = &mut self.streaming {
+            driver.stream(&mut streaming.steps).await;
+            self.finish_streaming(driver).await;
+        }
+    }
+
+    async fn finish_streaming<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+    ) {
+        if self
+            .streaming
+            .take()
+            .is_some_and(|streaming| streaming.stops)
+        {
+            driver.finish().await;
+        }
+    }
+
+    /// Run every planned move to the end, reporting the commands waiting on them
+    async fn finish_moves<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        status_tx: &StatusTx,
+    ) {
+        loop {
+            self.stream(driver).await;
+            if !self.start_next(status_tx).await {
+                break;
+            }
+        }
+    }
+
+    /// Get on with the moves at the front of the planner until there's room for another
+    async fn make_room<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        status_tx: &StatusTx,
+    ) {
+        while self.planner.is_full() {
+            self.stream(driver).await;
+            self.start_next(status_tx).await;
+        }
+    }
+
+    /// Report a command as done, once every move planned before it is
+    async fn finish_command<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        status_tx: &StatusTx,
+        command_id: CommandId,
+    ) {
+        if self.planner.is_empty() && self.streaming.is_none() {
+            info!("command {} done", command_id);
+            status_tx
+                .send(MotionStatusMsg::CommandFinished(command_id))
+                .await;
+        } else {
+            self.make_room(driver, status_tx).await;
+            self.planner.push_done(command_id);
+        }
+    }
+
+    /// Set the park position of the given axes (in millimeters or rotations), or of every axis to
+    /// wherever it is now
+    fn set_park_position(&mut self, pos: Option<Pos<AXES>>) {
+        match pos {
+            Some(pos) => {
+                for (axis, coord) in pos.0.into_iter().enumerate() {
+                    if let Some(coord) = coord {
+                        self.park_position[axis] = Some(self.coord_from_units(axis, coord));
                     }
                 }
-                Command::Home => {
-                    let speed =
-                        [HOME_SPEED; 2].zip_with([self.axes[0], self.axes[1]], |speed, axis| {
-                            match axis.unit {
-                                AxisUnit::Millimeters => {
-                                    speed.to_steps_per_second(axis.microns_per_step)
-                                }
-                                AxisUnit::Rotations => {
-                                    StepsPerSecond(0) /* can't home non-distance axes */
-                                }
-                            }
-                        });
-                    driver.home(speed).await;
-                    self.is_homed = true;
-                    for coord in self.position.each_mut() {
-                        *coord = UCoord::ZERO;
+            }
+            None => self.park_position = self.position.map(Some),
+        }
+    }
+
+    /// Forget where we are, eg because the steppers were disabled
+    fn lose_position(&mut self) {
+        self.is_homed = [false; AXES];
+        for coord in self.position.each_mut() {
+            *coord = ICoord::ZERO;
+        }
+    }
+
+    /// Stop dead, part way through whatever we were doing
+    async fn emergency_stop<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+    ) {
+        warn!("emergency stop");
+        driver.emergency_stop().await;
+        self.streaming = None;
+        self.planner.clear();
+        // There's no telling how far the move that was cut short got
+        self.lose_position();
+    }
+
+    /// Carry out a command, or say why it can't be
+    async fn execute<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        status_tx: &StatusTx,
+        command: Command<AXES>,
+    ) -> Result<(), &'static str> {
+        match command {
+            // Subroutines and loops are expanded by the server, so they never get here. A comment can still
+            // come with an id, to report when a whole subroutine call is done
+            Command::Stop
+            | Command::Comment
+            | Command::DefineSubroutine(_)
+            | Command::EndSubroutine(_)
+            | Command::CallSubroutine(_)
+            | Command::Repeat(..)
+            | Command::EndRepeat(_)
+            | Command::While(..)
+            | Command::EndWhile(_)
+            | Command::SetParameter(..) => {}
+            Command::WaitForMotion => self.finish_moves(driver, status_tx).await,
+            // Handled by the server, which signals us directly for an emergency stop, and reads the
+            // endstops itself so it can report them while we're busy
+            Command::EmergencyStop
+            | Command::Reset
+            | Command::GetFirmwareInfo
+            | Command::GetEndstopStatus => {}
+            Command::Dwell(duration) => {
+                self.finish_moves(driver, status_tx).await;
+                Timer::after_millis(duration.as_millis() as _).await;
+            }
+            Command::EnableAllSteppers => {
+                self.finish_moves(driver, status_tx).await;
+                info!("enabling steppers");
+                driver.set_sleep(false).await
+            }
+            Command::DisableAllSteppers => {
+                self.finish_moves(driver, status_tx).await;
+                info!("disabling steppers");
+                driver.set_sleep(true).await;
+
+                // If we disable the motors, we have to assume we don't know where we are
+                // anymore
+                self.lose_position();
+            }
+            Command::Home(axes) => {
+                self.finish_moves(driver, status_tx).await;
+                // With no axes given, home all of them
+                let axes = if axes.contains(&true) {
+                    axes
+                } else {
+                    [true; AXES]
+                };
+                let speeds = array::from_fn(|axis| {
+                    match self.axes[axis] {
+                        Axis::Linear(_) if axes[axis] => {
+                            let speed = self.axes[axis].steps_per_second(HOME_SPEED.0);
+                            Some(match self.max_steps_per_second(axis) {
+                                Some(max) => speed.min(max),
+                                None => speed,
+                            })
+                        }
+                        // Can't home non-distance axes, so they just get zeroed where they are
+                        _ => None,
                     }
-                }
-                Command::RapidMove(target_pos) | Command::LinearMove(target_pos) => {
-                    if let Some(feedrate) = target_pos.0[3 /* feedrate is the last axis */] {
-                        self.feedrate = MillimetersPerSecond(feedrate);
+                });
+                driver.home(speeds).await;
+                for (axis, homed) in axes.into_iter().enumerate() {
+                    if homed {
+                        self.is_homed[axis] = true;
+                        self.position[axis] = ICoord::ZERO;
                     }
+                }
+            }
+            Command::RapidMove(mv) | Command::LinearMove(mv) => {
+                if let Some(feedrate) = mv.feedrate {
+                    self.feedrate = self.feedrate_from_units(feedrate);
+                }
 
-                    let target_pos = [target_pos.0[0], target_pos.0[1], target_pos.0[2]];
-
-                    let mut dist =
-                        self.position
-                            .each_mut()
-                            .zip_with(target_pos, |p1, p2| match p2 {
-                                Some(target_pos) => {
-                                    let res = diff(target_pos, *p1);
-                                    // TODO(aspen): Don't update position until after moving, to
-                                    // handle canceled moves
-                                    *p1 = target_pos;
-                                    res
-                                }
-                                None => ICoord::ZERO,
-                            });
-                    dist[2] = dist[2].saturating_neg();
-
-                    let steps = dist.zip_with(self.axes, |dist, axis| -> i32 {
-                        match axis.unit {
-                            AxisUnit::Millimeters => {
-                                let microns = dist * ICoord::from_num(1000);
-                                let steps = microns / axis.microns_per_step.0;
-                                steps.saturating_cast()
-                            }
-                            AxisUnit::Rotations => {
-                                // Dist is in rotations
-                                ((dist * 360) / axis.degrees_per_step.0).saturating_cast()
-                            }
+                let distance_mode = self.distance_mode;
+                let target_pos = array::from_fn(|axis| {
+                    mv.target.0[axis].map(|coord| {
+                        let coord = self.coord_from_units(axis, coord);
+                        match distance_mode {
+                            DistanceMode::Absolute => coord,
+                            DistanceMode::Relative => self.position[axis].saturating_add(coord),
                         }
-                    });
-
-                    let speed = if dist[2].is_zero() {
-                        if dist[1].is_zero() {
-                            [
-                                self.feedrate
-                                    .to_steps_per_second(self.axes[0].microns_per_step),
-                                StepsPerSecond(0),
-                                StepsPerSecond(0),
-                            ]
-                        } else if dist[0].is_zero() {
-                            [
-                                StepsPerSecond(0),
-                                self.feedrate
-                                    .to_steps_per_second(self.axes[1].microns_per_step),
-                                StepsPerSecond(0),
-                            ]
+                    })
+                });
+                self.move_to(driver, status_tx, target_pos).await;
+            }
+            Command::GetCurrentPosition => {
+                // The position is updated as moves are planned, so it's only where the axes are
+                // once they've all been run
+                self.finish_moves(driver, status_tx).await;
+                let position = Position {
+                    coords: array::from_fn(|axis| self.coord_to_units(axis, self.position[axis])),
+                    feedrate: self.feedrate_to_units(self.feedrate),
+                    units: self.units,
+                };
+                info!("{}", position);
+                status_tx.send(MotionStatusMsg::Position(position)).await;
+            }
+            Command::SetStepsPerUnit(steps) if steps.0.iter().all(Option::is_none) => {
+                let steps = StepsPerUnit {
+                    steps: array::from_fn(|axis| {
+                        // An inch takes 25.4 times as many steps as a millimeter
+                        let steps = self.axes[axis].steps_per_unit();
+                        if self.is_in_inches(axis) {
+                            steps.saturating_mul(MILLIMETERS_PER_INCH)
                         } else {
-                            // if c isn't moving, base the feedrate calculation on a triangle
-                            let x_fr = {
-                                let z_over_x = dist[1].unsigned_abs() / dist[0].unsigned_abs();
-                                self.feedrate.0
-                                    / (z_over_x * z_over_x + UCoord::from_num(1)).fast_sqrt()
-                            };
-                            let z_fr = {
-                                let x_over_z = dist[0].unsigned_abs() / dist[1].unsigned_abs();
-                                self.feedrate.0
-                                    / (x_over_z * x_over_z + UCoord::from_num(1)).fast_sqrt()
-                            };
-                            [
-                                MillimetersPerSecond(x_fr)
-                                    .to_steps_per_second(self.axes[0].microns_per_step),
-                                MillimetersPerSecond(z_fr)
-                                    .to_steps_per_second(self.axes[1].microns_per_step),
-                                StepsPerSecond(0),
-                            ]
+                            steps
                         }
-                    } else {
-                        let c_speed = self
-                            .feedrate
-                            .to_steps_per_second(self.axes[2].microns_per_step);
-                        let dur_s =
-                            UCoord::from_num(steps[2].unsigned_abs()) / UCoord::from_num(c_speed.0);
-                        [
-                            StepsPerSecond(
-                                (UCoord::from_num(steps[0].unsigned_abs()) / dur_s)
-                                    .saturating_to_num(),
-                            ),
-                            StepsPerSecond(
-                                (UCoord::from_num(steps[1].unsigned_abs()) / dur_s)
-                                    .saturating_to_num(),
-                            ),
-                            c_speed,
-                        ]
-                    };
-
-                    driver.do_move(steps, speed).await;
+                    }),
+                    units: self.units,
+                };
+                info!("{}", steps);
+                status_tx.send(MotionStatusMsg::StepsPerUnit(steps)).await;
+            }
+            Command::SetStepsPerUnit(steps) => {
+                for (axis, steps) in steps.0.into_iter().enumerate() {
+                    if let Some(steps) = steps {
+                        let steps = if self.is_in_inches(axis) {
+                            steps / MILLIMETERS_PER_INCH
+                        } else {
+                            steps
+                        };
+                        self.axes[axis].set_steps_per_unit(steps);
+                    }
                 }
-                Command::GetCurrentPosition => {
-                    let [x, z, c] = self.position;
-                    let f = self.feedrate;
-                    info!(
-                        "X{} Z{} C{} F{}",
-                        Display2Format(&x),
-                        Display2Format(&z),
-                        Display2Format(&c),
-                        f
-                    );
+            }
+            Command::SetMaxFeedrate(feedrates) => {
+                for (axis, feedrate) in feedrates.0.into_iter().enumerate() {
+                    if let Some(feedrate) = feedrate {
+                        self.max_feedrate[axis] =
+                            Some(self.coord_from_units(axis, feedrate).unsigned_abs());
+                    }
+                }
+            }
+            Command::SetMaxAcceleration(accelerations) => {
+                for (axis, acceleration) in accelerations.0.into_iter().enumerate() {
+                    if let Some(acceleration) = acceleration {
+                        self.max_acceleration[axis] =
+                            Some(self.coord_from_units(axis, acceleration).unsigned_abs());
+                    }
+                }
+            }
+            Command::SetAcceleration(acceleration) => {
+                // Converts between units just like a feedrate
+                self.acceleration = self.feedrate_from_units(acceleration).0;
+            }
+            Command::SetDistanceMode(distance_mode) => {
+                self.distance_mode = distance_mode;
+            }
+            Command::SetUnits(units) => {
+                self.units = units;
+            }
+            Command::SetPosition(pos) => {
+                for (axis, new_coord) in pos.0.into_iter().enumerate() {
+                    if let Some(new_coord) = new_coord {
+                        self.position[axis] = self.coord_from_units(axis, new_coord);
+                    }
+                }
+            }
+            Command::SetParkPosition(pos) => self.set_park_position(pos),
+            Command::Park(pos) => {
+                let target_pos = match pos {
+                    Some(pos) => array::from_fn(|axis| {
+                        pos.0[axis].map(|coord| self.coord_from_units(axis, coord))
+                    }),
+                    None => self.park_position,
+                };
+                // We don't know where the park position is until we've homed
+                if target_pos
+                    .iter()
+                    .zip(self.is_homed)
+                    .all(|(coord, is_homed)| coord.is_none() || is_homed)
+                {
+                    self.move_to(driver, status_tx, target_pos).await;
+                } else {
+                    return Err("can't park before homing");
                 }
-                Command::Park(_) => {}
             }
-            info!("command {} done", command_id);
-            status_tx
-                .send(MotionStatusMsg::CommandFinished(command_id))
-                .await;
+        }
+        Ok(())
+    }
+
+    /// Do whatever's next: carry on streaming the move being run, and meanwhile take in the next
+    /// command, if there's room to plan it
+    async fn step<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        &mut self,
+        driver: &mut driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        command_rx: &channel::Receiver<
+            'static,
+            impl RawMutex,
+            (Option<CommandId>, Command<AXES>),
+            COMMAND_BUFFER_SIZE,
+        >,
+        status_tx: &StatusTx,
+    ) {
+        // Start the next planned move straight away if the axes are already moving, so they don't
+        // run out of steps, and otherwise once there's nothing more to look ahead at
+        if self.streaming.is_none()
+            && (driver.is_moving() || self.planner.is_full() || command_rx.is_empty())
+        {
+            self.start_next(status_tx).await;
+        }
+
+        let has_room = !self.planner.is_full();
+        let streaming = &mut self.streaming;
+        let stream = async {
+            match streaming {
+                Some(streaming) => driver.stream(&mut streaming.steps).await,
+                None => future::pending().await,
+            }
+        };
+        let receive = async {
+            match has_room {
+                true => command_rx.receive().await,
+                false => future::pending().await,
+            }
+        };
+        match select(stream, receive).await {
+            Either::First(()) => self.finish_streaming(driver).await,
+            Either::Second((command_id, command)) => {
+                info!("got command");
+                match self.execute(driver, status_tx, command).await {
+                    Ok(()) => {
+                        if let Some(command_id) = command_id {
+                            self.finish_command(driver, status_tx, command_id).await;
+                        }
+                    }
+                    Err(reason) => {
+                        warn!("command failed: {}", reason);
+                        status_tx
+                            .send(MotionStatusMsg::CommandFailed(command_id, reason))
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn run<const XSM: usize, const CSM: usize, const ZSM: usize>(
+        mut self,
+        mut driver: driver::Driver<'static, impl pio::Instance, XSM, CSM, ZSM>,
+        command_rx: channel::Receiver<
+            'static,
+            impl RawMutex,
+            (Option<CommandId>, Command<AXES>),
+            COMMAND_BUFFER_SIZE,
+        >,
+        status_tx: StatusTx,
+        emergency_stop: &Signal<CriticalSectionRawMutex, ()>,
+    ) -> ! {
+        loop {
+            let step = self.step(&mut driver, &command_rx, &status_tx);
+            if let Either::Second(()) = select(step, emergency_stop.wait()).await {
+                self.emergency_stop(&mut driver).await;
+            }
         }
     }
 }
@@ -277,9 +773,70 @@ mod tests {
 
     #[test]
     fn four_minus_five() {
-        let four = UCoord::from_str("4").unwrap();
-        let five = UCoord::from_str("4").unwrap();
+        let four = ICoord::from_str("4").unwrap();
+        let five = ICoord::from_str("5").unwrap();
         let res = diff(four, five);
         assert_eq!(res, ICoord::from_str("-1").unwrap());
     }
+
+    #[test]
+    fn steps_per_unit() {
+        let mut axis = Axis::Linear(ICoord::from_num(12).into());
+        assert_eq!(
+            axis.steps_per_unit(),
+            ICoord::from_num(1000) / ICoord::from_num(12)
+        );
+        axis.set_steps_per_unit(ICoord::from_num(200));
+        assert_eq!(axis, Axis::Linear(ICoord::from_num(5).into()));
+
+        let mut axis = Axis::Rotational(ICoord::from_num(3200).into());
+        assert_eq!(axis.steps_per_unit(), ICoord::from_num(3200));
+        axis.set_steps_per_unit(ICoord::from_num(6400));
+        assert_eq!(axis.steps_per_unit(), ICoord::from_num(6400));
+    }
+
+    #[test]
+    fn c_speed_follows_steps_per_unit() {
+        let mut state = State::new(
+            [
+                Axis::Linear(ICoord::from_num(12).into()),
+                Axis::Linear(ICoord::from_num(6).into()),
+                Axis::Rotational(ICoord::from_num(3200).into()),
+            ],
+            [Some(ICoord::ZERO), Some(ICoord::ZERO), None],
+        );
+        let one_rotation = [ICoord::ZERO, ICoord::ZERO, ICoord::ONE];
+        // At the default feedrate of one rotation a second
+        let (steps, speeds) = state.steps_and_speeds(one_rotation);
+        assert_eq!(steps[2], 3200);
+        assert_eq!(speeds[2], StepsPerSecond(3200));
+
+        // M92 C6400
+        state.axes[2].set_steps_per_unit(ICoord::from_num(6400));
+        let (steps, speeds) = state.steps_and_speeds(one_rotation);
+        assert_eq!(steps[2], 6400);
+        assert_eq!(speeds[2], StepsPerSecond(6400));
+    }
+
+    #[test]
+    fn set_park_position() {
+        let axis = || Axis::Linear(ICoord::from_num(12).into());
+        let mut state = State::new(
+            [axis(), axis(), axis()],
+            [Some(ICoord::ZERO), Some(ICoord::ZERO), None],
+        );
+        state.set_park_position(Some(Pos([None, Some(ICoord::from_num(5)), None])));
+        assert_eq!(
+            state.park_position,
+            [Some(ICoord::ZERO), Some(ICoord::from_num(5)), None]
+        );
+
+        // Parking where the axes are now
+        state.position = [1, 2, 3].map(ICoord::from_num);
+        state.set_park_position(None);
+        assert_eq!(
+            state.park_position,
+            [1, 2, 3].map(|c| Some(ICoord::from_num(c)))
+        );
+    }
 }
@@ -0,0 +1,257 @@
+//! Look-ahead planning. Moves are queued up before they're run, so that each one can carry on from
+//! the one before without stopping in between, as long as no axis has to change speed too suddenly
+//! at the junction. Every move still has to be able to stop by the end of the queue, in case
+//! nothing comes after it
+
+use defmt::{warn, Format};
+use heapless::Deque;
+
+use crate::{
+    driver::StepsPerSecond,
+    ramp::{self, Profile, FULL_SPEED},
+    CommandId, AXES,
+};
+
+/// How many moves (and commands waiting on them) can be queued up at once
+pub const PLANNER_SIZE: usize = 16;
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct PlannedMove {
+    steps: [i32; AXES],
+    /// How fast each axis goes at full speed, in steps per second, negative going backwards
+    velocities: [i64; AXES],
+    /// How long the move takes at full speed, in [`crate::driver::TICKS_PER_SECOND`]
+    ticks: u64,
+    /// How long it takes to get up to full speed from a stop
+    ramp_ticks: u64,
+    /// The fastest the move can start, given the junction with the move before it, out of
+    /// [`FULL_SPEED`]
+    max_entry: u64,
+    /// How fast the move starts, out of [`FULL_SPEED`]
+    entry: u64,
+}
+
+impl PlannedMove {
+    pub fn new(
+        steps: [i32; AXES],
+        speeds: [StepsPerSecond; AXES],
+        ticks: u64,
+        ramp_ticks: u64,
+    ) -> Self {
+        let mut velocities = [0; AXES];
+        for (axis, velocity) in velocities.iter_mut().enumerate() {
+            *velocity = i64::from(speeds[axis].0) * i64::from(steps[axis].signum());
+        }
+        Self {
+            steps,
+            velocities,
+            ticks,
+            ramp_ticks,
+            max_entry: FULL_SPEED,
+            entry: 0,
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.velocities.iter().any(|&velocity| velocity != 0)
+    }
+
+    /// The fastest the move can be going at its end, if it starts at `entry`
+    fn reachable(&self, entry: u64) -> u64 {
+        ramp::reachable(entry, self.ticks, self.ramp_ticks)
+    }
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    Move(PlannedMove),
+    /// A command that's done once every move before it is
+    Done(CommandId),
+}
+
+/// What to do next, from [`Planner::pop`]
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub enum Planned {
+    Move {
+        steps: [i32; AXES],
+        profile: Profile,
+    },
+    Done(CommandId),
+}
+
+/// The fastest two moves can be going at the junction between them, out of [`FULL_SPEED`] of
+/// each, without any axis's speed jumping by more than `max_jumps`
+fn junction_speed(
+    before: [i64; AXES],
+    after: [i64; AXES],
+    max_jumps: [StepsPerSecond; AXES],
+) -> u64 {
+    (0..AXES)
+        .map(|axis| match before[axis].abs_diff(after[axis]) {
+            0 => FULL_SPEED,
+            jump => (u64::from(max_jumps[axis].0) * FULL_SPEED / jump).min(FULL_SPEED),
+        })
+        .min()
+        .unwrap_or(FULL_SPEED)
+}
+
+pub struct Planner {
+    queue: Deque<Entry, PLANNER_SIZE>,
+    /// How fast the first move in the queue starts: as fast as the move before it ended
+    start_speed: u64,
+    /// The velocities of the last move queued, for the junction with the next one
+    last_velocities: [i64; AXES],
+}
+
+impl Planner {
+    pub const fn new() -> Self {
+        Self {
+            queue: Deque::new(),
+            start_speed: 0,
+            last_velocities: [0; AXES],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.is_full()
+    }
+
+    /// Forget everything queued, eg after an emergency stop, and start again from a stop
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    fn push(&mut self, entry: Entry) {
+        if self.queue.push_back(entry).is_err() {
+            warn!("planner full, dropping {}", entry);
+        }
+    }
+
+    /// Queue a move after the others. `max_jumps` is how much each axis's speed can change all at
+    /// once at the junction with the move before, in steps per second
+    pub fn push_move(&mut self, mut mv: PlannedMove, max_jumps: [StepsPerSecond; AXES]) {
+        // A move that doesn't go anywhere doesn't make a junction, so it just passes the speed
+        // through from the move before it to the move after it
+        if mv.is_moving() {
+            mv.max_entry = junction_speed(self.last_velocities, mv.velocities, max_jumps);
+            self.last_velocities = mv.velocities;
+        }
+        self.push(Entry::Move(mv));
+        self.recalculate();
+    }
+
+    /// Queue a command to report as done once every move queued before it is
+    pub fn push_done(&mut self, command_id: CommandId) {
+        self.push(Entry::Done(command_id));
+    }
+
+    /// Work out how fast every queued move starts
+    fn recalculate(&mut self) {
+        // Going backwards, every move has to be able to slow down in time for the next one, and
+        // the last one has to be able to stop
+        let mut exit = 0;
+        for entry in self.queue.iter_mut().rev() {
+            if let Entry::Move(mv) = entry {
+                mv.entry = mv.max_entry.min(mv.reachable(exit));
+                exit = mv.entry;
+            }
+        }
+        // Then going forwards, every move has to be able to speed up in time for the next one. The
+        // first one is already set, by the move before it
+        let mut entry_speed = None;
+        for entry in self.queue.iter_mut() {
+            if let Entry::Move(mv) = entry {
+                mv.entry = match entry_speed {
+                    Some(entry_speed) => mv.entry.min(entry_speed),
+                    None => self.start_speed,
+                };
+                entry_speed = Some(mv.reachable(mv.entry));
+            }
+        }
+    }
+
+    /// Take the next thing to do off the front of the queue. A move's exit speed is fixed once it's
+    /// taken, since the next move has to start at it
+    pub fn pop(&mut self) -> Option<Planned> {
+        match self.queue.pop_front()? {
+            Entry::Done(command_id) => Some(Planned::Done(command_id)),
+            Entry::Move(mv) => {
+                let exit = self
+                    .queue
+                    .iter()
+                    .find_map(|entry| match entry {
+                        Entry::Move(next) => Some(next.entry),
+                        Entry::Done(_) => None,
+                    })
+                    .unwrap_or(0);
+                self.start_speed = exit;
+                Some(Planned::Move {
+                    steps: mv.steps,
+                    profile: Profile::new(mv.ticks, mv.ramp_ticks, mv.entry, exit),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[embedded_test::tests]
+mod tests {
+    use super::*;
+    use crate::driver::TICKS_PER_SECOND;
+
+    const SECOND: u64 = TICKS_PER_SECOND;
+    const JUMPS: [StepsPerSecond; AXES] = [StepsPerSecond(10); AXES];
+
+    fn planned(steps: [i32; AXES]) -> PlannedMove {
+        PlannedMove::new(
+            steps,
+            steps.map(|_| StepsPerSecond(1000)),
+            SECOND,
+            SECOND / 5,
+        )
+    }
+
+    /// Take the next move, and how fast it ends
+    fn exit_speed(planner: &mut Planner) -> u64 {
+        match planner.pop() {
+            Some(Planned::Move { .. }) => planner.start_speed,
+            _ => panic!("expected a move"),
+        }
+    }
+
+    #[test]
+    fn blends_moves_in_a_line() {
+        let mut planner = Planner::new();
+        planner.push_move(planned([0, 0, 1000]), JUMPS);
+        planner.push_move(planned([0, 0, 1000]), JUMPS);
+        // Carries on at full speed into the second move, which then stops
+        assert_eq!(exit_speed(&mut planner), FULL_SPEED);
+        assert_eq!(exit_speed(&mut planner), 0);
+        assert!(planner.is_empty());
+    }
+
+    #[test]
+    fn slows_down_for_corners() {
+        let mut planner = Planner::new();
+        planner.push_move(planned([0, 0, 1000]), JUMPS);
+        planner.push_move(planned([0, 0, -1000]), JUMPS);
+        // Turning around changes speed by twice the full speed
+        assert_eq!(exit_speed(&mut planner), 10 * FULL_SPEED / 2000);
+    }
+
+    #[test]
+    fn reports_commands_after_the_moves_before_them() {
+        let mut planner = Planner::new();
+        planner.push_move(planned([0, 0, 1000]), JUMPS);
+        planner.push_done(CommandId(1));
+        planner.push_move(planned([0, 0, 1000]), JUMPS);
+        // The command doesn't stop the moves either side of it
+        assert_eq!(exit_speed(&mut planner), FULL_SPEED);
+        assert_eq!(planner.pop(), Some(Planned::Done(CommandId(1))));
+    }
+}
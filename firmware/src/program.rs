@@ -0,0 +1,406 @@
+//! Subroutines and loops, run on the firmware.
+//!
+//! Subroutines are defined over the wire with `O<n> sub` ... `O<n> endsub` and kept on the firmware,
+//! so that eg a layer of a coil can be sent once and then called for every layer. Loops
+//! (`O<n> repeat` and `O<n> while`) sent outside of a subroutine are recorded the same way, and run
+//! as soon as they're complete. The server keeps reading while it's running one, so an M0 or M112
+//! can stop it part way through, and a `while` loop that never ends gives up after
+//! [`MAX_ITERATIONS`]
+
+use core::{fmt, ops::Range, ptr};
+
+use defmt::{Display2Format, Format, Formatter};
+use gcode::{Command, Parameters};
+use heapless::Vec;
+
+use crate::{AXES, AXIS_LABELS};
+
+/// Space for the text of every subroutine body, together
+pub const TEXT_SIZE: usize = 4096;
+pub const MAX_SUBROUTINES: usize = 16;
+/// How many subroutine calls and loops can be in progress at once, not counting the outermost one
+pub const MAX_DEPTH: usize = 8;
+/// How many times a `while` loop can run before it's taken to never end
+pub const MAX_ITERATIONS: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    UndefinedSubroutine(u32),
+    /// There's no room left to store another subroutine
+    OutOfSpace,
+    /// Subroutine calls and loops nested more than [`MAX_DEPTH`] deep
+    TooDeep,
+    /// A subroutine was defined inside another one
+    NestedDefinition,
+    /// An `O<n> endsub`, `O<n> endrepeat` or `O<n> endwhile` that doesn't match the block it's in,
+    /// or that came outside of any block
+    UnexpectedEnd,
+    /// A loop that's skipped over has no end
+    Unterminated(u32),
+    /// A `while` loop ran more than [`MAX_ITERATIONS`] times
+    TooManyIterations(u32),
+    /// A line that couldn't be parsed or evaluated when it was run, eg because of an undefined
+    /// parameter
+    BadLine(Option<gcode::Reason>),
+}
+
+impl From<gcode::Error<'_>> for Error {
+    fn from(err: gcode::Error<'_>) -> Self {
+        match err {
+            gcode::Error::ParseFailed(err) => Error::BadLine(Some(err.reason)),
+            _ => Error::BadLine(None),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UndefinedSubroutine(id) => write!(f, "undefined subroutine {id}"),
+            Error::OutOfSpace => f.write_str("out of space for subroutines"),
+            Error::TooDeep => write!(f, "calls and loops nested more than {MAX_DEPTH} deep"),
+            Error::NestedDefinition => f.write_str("subroutine defined inside another subroutine"),
+            Error::UnexpectedEnd => f.write_str("unexpected end of block"),
+            Error::Unterminated(id) => write!(f, "no end to loop {id}"),
+            Error::TooManyIterations(id) => {
+                write!(f, "loop {id} ran more than {MAX_ITERATIONS} times")
+            }
+            Error::BadLine(Some(reason)) => write!(f, "{reason}"),
+            Error::BadLine(None) => f.write_str("bad line"),
+        }
+    }
+}
+
+impl Format for Error {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "{}", Display2Format(self))
+    }
+}
+
+/// A block of lines being recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Subroutine(u32),
+    Loop(u32),
+}
+
+/// What happened to a line passed to [`Subroutines::record`]
+pub enum Recorded {
+    /// It was added to the block being recorded
+    Line,
+    /// It finished a subroutine definition
+    Subroutine,
+    /// It finished a loop, which is ready to run with [`Subroutines::last_loop`]
+    Loop,
+}
+
+/// The bodies of every defined subroutine, stored as the raw lines they were sent as
+pub struct Subroutines {
+    /// Every subroutine body, one after another, followed by the block being recorded (or the last
+    /// loop that was run)
+    text: Vec<u8, TEXT_SIZE>,
+    bodies: Vec<(u32, Range<usize>), MAX_SUBROUTINES>,
+    /// The block currently being recorded, and where it starts in `text`
+    recording: Option<(Block, usize)>,
+}
+
+impl Subroutines {
+    pub const fn new() -> Self {
+        Self {
+            text: Vec::new(),
+            bodies: Vec::new(),
+            recording: None,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Start defining subroutine `id`, replacing any existing definition of it
+    pub fn begin(&mut self, id: u32) -> Result<(), Error> {
+        self.start_recording(Block::Subroutine(id), b"")
+    }
+
+    /// Start recording a loop, from its `O<n> repeat` or `O<n> while` line
+    pub fn begin_loop(&mut self, id: u32, line: &[u8]) -> Result<(), Error> {
+        self.start_recording(Block::Loop(id), line)
+    }
+
+    fn start_recording(&mut self, block: Block, line: &[u8]) -> Result<(), Error> {
+        if self.is_recording() {
+            self.cancel();
+            return Err(Error::NestedDefinition);
+        }
+        if let Block::Subroutine(id) = block {
+            self.remove(id);
+        }
+        // Drop the last loop that was run
+        self.text.truncate(self.stored_len());
+        self.recording = Some((block, self.text.len()));
+        self.record_line(line)
+    }
+
+    /// Record a line of the block being recorded, finishing the block at its end
+    pub fn record(&mut self, command: Command<AXES>, line: &[u8]) -> Result<Recorded, Error> {
+        let Some((block, start)) = self.recording else {
+            return Err(Error::UnexpectedEnd);
+        };
+        match (block, command) {
+            (_, Command::DefineSubroutine(_)) => {
+                self.cancel();
+                Err(Error::NestedDefinition)
+            }
+            (Block::Subroutine(id), Command::EndSubroutine(end)) => {
+                // M99 doesn't say which subroutine it ends
+                if end.is_some_and(|end| end != id) {
+                    self.cancel();
+                    return Err(Error::UnexpectedEnd);
+                }
+                self.recording = None;
+                if self.bodies.push((id, start..self.text.len())).is_err() {
+                    self.text.truncate(start);
+                    return Err(Error::OutOfSpace);
+                }
+                Ok(Recorded::Subroutine)
+            }
+            (Block::Loop(id), Command::EndRepeat(end) | Command::EndWhile(end)) if end == id => {
+                self.record_line(line)?;
+                self.recording = None;
+                Ok(Recorded::Loop)
+            }
+            _ => {
+                self.record_line(line)?;
+                Ok(Recorded::Line)
+            }
+        }
+    }
+
+    fn record_line(&mut self, line: &[u8]) -> Result<(), Error> {
+        if self.text.extend_from_slice(line).is_err() {
+            self.cancel();
+            return Err(Error::OutOfSpace);
+        }
+        Ok(())
+    }
+
+    /// Throw away the block currently being recorded, if any
+    pub fn cancel(&mut self) {
+        if let Some((_, start)) = self.recording.take() {
+            self.text.truncate(start);
+        }
+    }
+
+    /// How much of `text` is subroutine bodies
+    fn stored_len(&self) -> usize {
+        self.bodies.iter().map(|(_, body)| body.len()).sum()
+    }
+
+    fn remove(&mut self, id: u32) {
+        let Some(idx) = self.bodies.iter().position(|(body_id, _)| *body_id == id) else {
+            return;
+        };
+        let (_, removed) = self.bodies.remove(idx);
+        let len = self.text.len();
+        self.text.copy_within(removed.end..len, removed.start);
+        self.text.truncate(len - removed.len());
+        for (_, body) in &mut self.bodies {
+            if body.start >= removed.end {
+                *body = body.start - removed.len()..body.end - removed.len();
+            }
+        }
+    }
+
+    fn body(&self, id: u32) -> Result<&[u8], Error> {
+        self.bodies
+            .iter()
+            .find(|(body_id, _)| *body_id == id)
+            .map(|(_, body)| &self.text[body.clone()])
+            .ok_or(Error::UndefinedSubroutine(id))
+    }
+
+    /// The commands run by calling subroutine `id`
+    pub fn call<'a>(
+        &'a self,
+        id: u32,
+        parameters: &'a mut Parameters,
+    ) -> Result<Expansion<'a>, Error> {
+        Ok(Expansion::new(self, self.body(id)?, parameters))
+    }
+
+    /// The commands run by the last loop that was recorded
+    pub fn last_loop<'a>(&'a self, parameters: &'a mut Parameters) -> Expansion<'a> {
+        Expansion::new(self, &self.text[self.stored_len()..], parameters)
+    }
+}
+
+/// A subroutine call or loop in progress
+enum Frame<'a> {
+    /// A subroutine call, and the rest of the body it was called from
+    Call { ret: &'a [u8] },
+    /// A repeat loop, with the start of its body and how many more times to run it after this one
+    Repeat {
+        id: u32,
+        body: &'a [u8],
+        remaining: u32,
+    },
+    /// A while loop, starting from the `O<n> while` line that checks its condition, and how many
+    /// times it's run so far
+    While {
+        id: u32,
+        start: &'a [u8],
+        iterations: u32,
+    },
+}
+
+/// An iterator over the commands run by a block of lines, with subroutine calls and loops expanded
+/// in place. Each line is evaluated when it's reached, setting parameters along the way. Once it
+/// returns an error, it's done
+pub struct Expansion<'a> {
+    subroutines: &'a Subroutines,
+    parameters: &'a mut Parameters,
+    /// The rest of the body being run
+    rest: &'a [u8],
+    /// The calls and loops in progress, innermost last
+    stack: Vec<Frame<'a>, MAX_DEPTH>,
+}
+
+impl<'a> Expansion<'a> {
+    fn new(subroutines: &'a Subroutines, body: &'a [u8], parameters: &'a mut Parameters) -> Self {
+        Self {
+            subroutines,
+            parameters,
+            rest: body,
+            stack: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, frame: Frame<'a>) -> Result<(), Error> {
+        self.stack.push(frame).map_err(|_| Error::TooDeep)
+    }
+
+    /// Return from the innermost subroutine call, abandoning any loops inside it. Returns false if
+    /// we're returning from the outermost body, and so are done
+    fn ret(&mut self) -> bool {
+        while let Some(frame) = self.stack.pop() {
+            if let Frame::Call { ret } = frame {
+                self.rest = ret;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Skip past the end of a loop that isn't going to run (again), without evaluating anything
+    fn skip_loop(&mut self, id: u32) -> Result<(), Error> {
+        while !self.rest.is_empty() {
+            let (rest, line) = gcode::parse_single_command_unevaluated(AXIS_LABELS, self.rest)?;
+            self.rest = rest;
+            if let Command::EndRepeat(end) | Command::EndWhile(end) = line.command {
+                if end == id {
+                    return Ok(());
+                }
+            }
+        }
+        Err(Error::Unterminated(id))
+    }
+
+    fn step(&mut self) -> Result<Option<Command<AXES>>, Error> {
+        loop {
+            if self.rest.is_empty() {
+                if !self.ret() {
+                    return Ok(None);
+                }
+                continue;
+            }
+            let start = self.rest;
+            let (rest, line) =
+                gcode::parse_single_command_with(self.parameters, AXIS_LABELS, start)?;
+            self.rest = rest;
+
+            match line.command {
+                Command::Comment => {}
+                Command::SetParameter(number, value) => self.parameters.set(number, value),
+                Command::CallSubroutine(id) => {
+                    let body = self.subroutines.body(id)?;
+                    self.push(Frame::Call { ret: rest })?;
+                    self.rest = body;
+                }
+                Command::EndSubroutine(_) => {
+                    if !self.ret() {
+                        return Ok(None);
+                    }
+                }
+                Command::Repeat(id, 0) => self.skip_loop(id)?,
+                Command::Repeat(id, count) => self.push(Frame::Repeat {
+                    id,
+                    body: rest,
+                    remaining: count - 1,
+                })?,
+                Command::While(id, cond) => {
+                    let iterations = match self.stack.last() {
+                        // Back round to check the condition again
+                        Some(Frame::While {
+                            start: loop_start,
+                            iterations,
+                            ..
+                        }) if ptr::eq(*loop_start, start) => {
+                            let iterations = *iterations;
+                            self.stack.pop();
+                            iterations
+                        }
+                        _ => 0,
+                    };
+                    if !cond {
+                        self.skip_loop(id)?;
+                    } else if iterations >= MAX_ITERATIONS {
+                        return Err(Error::TooManyIterations(id));
+                    } else {
+                        self.push(Frame::While {
+                            id,
+                            start,
+                            iterations: iterations + 1,
+                        })?;
+                    }
+                }
+                Command::EndRepeat(end) => match self.stack.last_mut() {
+                    Some(Frame::Repeat {
+                        id,
+                        body,
+                        remaining,
+                    }) if *id == end => {
+                        if *remaining > 0 {
+                            *remaining -= 1;
+                            self.rest = body;
+                        } else {
+                            self.stack.pop();
+                        }
+                    }
+                    _ => return Err(Error::UnexpectedEnd),
+                },
+                Command::EndWhile(end) => match self.stack.last() {
+                    Some(Frame::While { id, start, .. }) if *id == end => {
+                        // Go back and check the condition again, leaving the loop in progress so
+                        // it can count how many times it's run
+                        self.rest = start;
+                    }
+                    _ => return Err(Error::UnexpectedEnd),
+                },
+                command => return Ok(Some(command)),
+            }
+        }
+    }
+}
+
+impl Iterator for Expansion<'_> {
+    type Item = Result<Command<AXES>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.step();
+        if res.is_err() {
+            self.rest = &[];
+            self.stack.clear();
+        }
+        res.transpose()
+    }
+}
@@ -0,0 +1,282 @@
+//! Trapezoidal speed profiles: every move speeds up at a constant acceleration, cruises at full
+//! speed, then slows down at the same rate to its end. Moves start and end at whatever speed the
+//! planner gives them, so that they can carry on from one to the next.
+//!
+//! A [`Profile`] only says how long each part of a move takes. The leading axis, the one with the
+//! most steps to take, follows it, and the other axes step along with it (see
+//! [`crate::interpolate`])
+
+use defmt::Format;
+
+use crate::driver::TICKS_PER_SECOND;
+
+/// Speeds are given as a fraction of a move's full speed, out of this
+pub const FULL_SPEED: u64 = 1 << 16;
+
+/// How many segments each ramp is split into. More segments make for a smoother ramp, but each one
+/// has to be computed and streamed to the PIO while the move is running
+const RAMP_SEGMENTS: u64 = 16;
+
+/// The shortest a segment of a ramp can be, so that short ramps aren't split up faster than their
+/// segments can be streamed
+const MIN_SEGMENT_TICKS: u64 = TICKS_PER_SECOND / 1000;
+
+/// The longest a move can take, about 38 hours, so that the arithmetic in [`Segments`] can't
+/// overflow
+const MAX_TICKS: u64 = 1 << 36;
+
+/// The fastest a move can be going at one end, if it's going at `speed` at the other and changes
+/// speed the whole way. The move would take `ticks` at full speed, and `ramp_ticks` to get up to
+/// full speed from a stop
+pub fn reachable(speed: u64, ticks: u64, ramp_ticks: u64) -> u64 {
+    // v² = u² + 2as, with the distance in ticks at full speed
+    let speed_up = 2 * u128::from(ticks.min(MAX_TICKS)) * u128::from(FULL_SPEED * FULL_SPEED)
+        / u128::from(ramp_ticks.max(1));
+    let squared = u128::from(speed * speed) + speed_up;
+    squared.min(u128::from(FULL_SPEED * FULL_SPEED)).isqrt() as u64
+}
+
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    /// How long it'd take to get from a stop up to full speed, in [`TICKS_PER_SECOND`]
+    ramp_ticks: u64,
+    /// Speeds at the start, the fastest point and the end of the move, out of [`FULL_SPEED`]
+    entry: u64,
+    peak: u64,
+    exit: u64,
+    /// How long is spent speeding up, at full speed, and slowing down
+    up_ticks: u64,
+    cruise_ticks: u64,
+    down_ticks: u64,
+}
+
+impl Profile {
+    /// The profile of a move that would take `ticks` at full speed, starting and ending at the
+    /// given speeds and accelerating at a rate that takes `ramp_ticks` to get up to full speed.
+    /// Moves too short to get all the way up to full speed only speed up for as long as they can
+    /// before slowing back down.
+    ///
+    /// The planner makes sure a move can always get from its entry speed to its exit speed, but if
+    /// it can't, the exit speed gives
+    pub fn new(ticks: u64, ramp_ticks: u64, entry: u64, exit: u64) -> Self {
+        let ticks = ticks.min(MAX_TICKS);
+        let ramp_ticks = ramp_ticks.clamp(1, MAX_TICKS);
+        let entry = entry.min(FULL_SPEED);
+        let exit = exit.clamp(
+            reachable_down(entry, ticks, ramp_ticks),
+            reachable(entry, ticks, ramp_ticks),
+        );
+
+        let full = u128::from(FULL_SPEED * FULL_SPEED);
+        let (entry_sq, exit_sq) = (u128::from(entry * entry), u128::from(exit * exit));
+        // How far it takes to get up to full speed and back down, in ticks at full speed
+        let ramps = u128::from(ramp_ticks) * (2 * full - entry_sq - exit_sq) / (2 * full);
+        let (peak, cruise_ticks) = if ramps <= u128::from(ticks) {
+            (FULL_SPEED, ticks - ramps as u64)
+        } else {
+            let peak_sq =
+                (2 * u128::from(ticks) * full / u128::from(ramp_ticks) + entry_sq + exit_sq) / 2;
+            (peak_sq.isqrt() as u64, 0)
+        };
+        let peak = peak.max(entry).max(exit);
+        Self {
+            ramp_ticks,
+            entry,
+            peak,
+            exit,
+            up_ticks: ramp_ticks * (peak - entry) / FULL_SPEED,
+            cruise_ticks,
+            down_ticks: ramp_ticks * (peak - exit) / FULL_SPEED,
+        }
+    }
+
+    /// Whether the move comes to a stop at its end
+    pub fn stops(&self) -> bool {
+        self.exit == 0
+    }
+
+    /// How long the whole move takes, in [`TICKS_PER_SECOND`]
+    pub fn total_ticks(&self) -> u64 {
+        self.up_ticks + self.cruise_ticks + self.down_ticks
+    }
+
+    fn slices(ticks: u64) -> u64 {
+        (ticks / MIN_SEGMENT_TICKS).clamp(1, RAMP_SEGMENTS)
+    }
+
+    /// The time at the end of segment `i`: the slices of the ramp up, then the cruise, then the
+    /// slices of the ramp down
+    fn boundary(&self, i: u64, up_slices: u64, down_slices: u64) -> u64 {
+        if i <= up_slices {
+            self.up_ticks * i / up_slices
+        } else {
+            let down = i - up_slices - 1;
+            self.up_ticks + self.cruise_ticks + self.down_ticks * down / down_slices
+        }
+    }
+
+    /// How far the move goes speeding up from `speed` for the given time, or slowing down to it.
+    /// Distances are all in units that keep them in whole numbers: twice the ramp time, times
+    /// [`FULL_SPEED`], times ticks at full speed
+    fn ramp_distance(&self, speed: u64, ticks: u64) -> u128 {
+        let (speed, ticks) = (u128::from(speed), u128::from(ticks));
+        2 * u128::from(self.ramp_ticks) * speed * ticks + u128::from(FULL_SPEED) * ticks * ticks
+    }
+
+    /// How far the move has gotten by the given time, out of [`Self::distance`]
+    fn distance_at(&self, ticks: u64) -> u128 {
+        let cruise_start = self.up_ticks;
+        let cruise_end = self.up_ticks + self.cruise_ticks;
+        if ticks <= cruise_start {
+            self.ramp_distance(self.entry, ticks)
+        } else if ticks <= cruise_end {
+            self.ramp_distance(self.entry, cruise_start)
+                + 2 * u128::from(self.ramp_ticks)
+                    * u128::from(self.peak)
+                    * u128::from(ticks - cruise_start)
+        } else {
+            let left = self.total_ticks().saturating_sub(ticks);
+            self.distance() - self.ramp_distance(self.exit, left)
+        }
+    }
+
+    fn distance(&self) -> u128 {
+        let cruise_end = self.up_ticks + self.cruise_ticks;
+        self.distance_at(cruise_end) + self.ramp_distance(self.exit, self.down_ticks)
+    }
+
+    /// The segments of the move, if it takes `steps` steps. A move with no steps to take still
+    /// waits for as long as it lasts
+    pub fn segments(self, steps: u32) -> Segments {
+        Segments {
+            profile: self,
+            up_slices: Self::slices(self.up_ticks),
+            down_slices: Self::slices(self.down_ticks),
+            steps,
+            next: 0,
+            taken: 0,
+            start_ticks: 0,
+        }
+    }
+}
+
+/// The slowest a move can be going at one end, if it's going at `speed` at the other and slows
+/// down the whole way
+fn reachable_down(speed: u64, ticks: u64, ramp_ticks: u64) -> u64 {
+    let slow_down = 2 * u128::from(ticks.min(MAX_TICKS)) * u128::from(FULL_SPEED * FULL_SPEED)
+        / u128::from(ramp_ticks.max(1));
+    u128::from(speed * speed).saturating_sub(slow_down).isqrt() as u64
+}
+
+/// A run of evenly spaced steps. A move is split into a series of these, so that it can speed up
+/// and slow down
+#[derive(Debug, Format, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// How many steps to take, or 0 to wait out the segment without stepping
+    pub steps: u32,
+    /// How long the whole segment takes, in [`TICKS_PER_SECOND`]
+    pub ticks: u64,
+}
+
+/// An iterator over the segments of a move
+pub struct Segments {
+    profile: Profile,
+    up_slices: u64,
+    down_slices: u64,
+    /// The steps over the whole move
+    steps: u32,
+    /// The next segment boundary
+    next: u64,
+    /// Steps taken by the segments so far
+    taken: u32,
+    /// When the next segment starts. A slice too short to have any steps in it is added on to the
+    /// one after it
+    start_ticks: u64,
+}
+
+impl Iterator for Segments {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        let distance = self.profile.distance();
+        while self.next < self.up_slices + 1 + self.down_slices {
+            self.next += 1;
+            let end_ticks = self
+                .profile
+                .boundary(self.next, self.up_slices, self.down_slices);
+            let reached = match distance {
+                // Too short to go anywhere, so take every step at once
+                0 => self.steps,
+                _ => {
+                    (u128::from(self.steps) * self.profile.distance_at(end_ticks) / distance) as u32
+                }
+            };
+            let steps = reached - self.taken;
+            if steps == 0 {
+                continue;
+            }
+            let ticks = end_ticks - self.start_ticks;
+            self.taken = reached;
+            self.start_ticks = end_ticks;
+            return Some(Segment { steps, ticks });
+        }
+        // Wait out the rest of the move without stepping
+        let ticks = self.profile.total_ticks() - self.start_ticks;
+        if ticks == 0 {
+            return None;
+        }
+        self.start_ticks += ticks;
+        Some(Segment { steps: 0, ticks })
+    }
+}
+
+#[cfg(test)]
+#[embedded_test::tests]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = TICKS_PER_SECOND;
+
+    #[test]
+    fn segments_add_up() {
+        // Long enough to get up to full speed, too short to, and carrying on from other moves
+        for profile in [
+            Profile::new(SECOND, SECOND / 5, 0, 0),
+            Profile::new(SECOND / 20, SECOND / 5, 0, 0),
+            Profile::new(SECOND, SECOND / 5, FULL_SPEED, FULL_SPEED / 2),
+        ] {
+            for steps in [0, 1, 7, 1000, 123_456] {
+                let (moved, ticks) = profile
+                    .segments(steps)
+                    .fold((0, 0), |(moved, ticks), segment| {
+                        (moved + segment.steps, ticks + segment.ticks)
+                    });
+                assert_eq!(moved, steps);
+                assert_eq!(ticks, profile.total_ticks());
+            }
+        }
+    }
+
+    #[test]
+    fn speeds_up_and_slows_down() {
+        let segments = heapless::Vec::<_, 64>::from_iter(
+            Profile::new(SECOND, SECOND / 5, 0, 0).segments(10_000),
+        );
+        let ticks_per_step = |segment: &Segment| segment.ticks / u64::from(segment.steps);
+        let fastest = segments.iter().map(ticks_per_step).min().unwrap();
+        assert!(ticks_per_step(segments.first().unwrap()) > fastest);
+        assert!(ticks_per_step(segments.last().unwrap()) > fastest);
+        // At full speed, the move would take a second
+        assert_eq!(fastest, SECOND / 10_000);
+    }
+
+    #[test]
+    fn carries_on() {
+        // Already at full speed, and staying there
+        let profile = Profile::new(SECOND, SECOND / 5, FULL_SPEED, FULL_SPEED);
+        assert_eq!(profile.total_ticks(), SECOND);
+        assert!(!profile.stops());
+        assert_eq!(reachable(0, SECOND / 10, SECOND / 5), FULL_SPEED);
+        assert!(reachable(0, SECOND / 40, SECOND / 5) < FULL_SPEED);
+    }
+}
@@ -1,12 +1,22 @@
+use core::{fmt, future};
 use cyw43::Control;
-use defmt::{debug, info, warn};
-use embassy_futures::select::{select, Either};
-use embassy_net::tcp::TcpSocket;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel};
+
+use defmt::{debug, info, warn, Display2Format, Format};
+use embassy_futures::{
+    select::{select, select3, Either, Either3},
+    yield_now,
+};
+use embassy_net::tcp::{Error, TcpSocket};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel, signal::Signal};
 use embassy_time::Duration;
 use embedded_io_async::Write;
 
-use crate::{blink_once, CommandId, MotionStatusMsg, AXES, AXIS_LABELS, COMMAND_BUFFER_SIZE, PORT};
+use crate::{
+    blink_once, driver,
+    program::{self, Expansion, Recorded, Subroutines},
+    units_name, CommandId, MotionStatusMsg, Position, StepsPerUnit, AXES, AXIS_LABELS,
+    COMMAND_BUFFER_SIZE, PORT,
+};
 
 pub struct Server {
     pub stack: embassy_net::Stack<'static>,
@@ -14,12 +24,17 @@ pub struct Server {
     pub command_tx: channel::Sender<
         'static,
         CriticalSectionRawMutex,
-        (CommandId, gcode::Command<AXES>),
+        (Option<CommandId>, gcode::Command<AXES>),
         COMMAND_BUFFER_SIZE,
     >,
     pub status_rx:
         channel::Receiver<'static, CriticalSectionRawMutex, MotionStatusMsg, COMMAND_BUFFER_SIZE>,
+    pub emergency_stop: &'static Signal<CriticalSectionRawMutex, ()>,
+    /// Read directly, rather than by the motion task, so M119 can be answered mid-move or mid-home
+    pub endstops: driver::Endstops,
     pub command_id_gen: u32,
+    /// Set by an emergency stop. Until it's cleared by M999, the machine won't do anything else
+    pub alarm: bool,
 }
 
 impl Server {
@@ -28,10 +43,205 @@ impl Server {
         CommandId(self.command_id_gen)
     }
 
+    /// Throw away everything queued, stop the motion task dead, and lock the machine until it's
+    /// reset
+    fn emergency_stop(&mut self) {
+        warn!("emergency stop");
+        self.alarm = true;
+        self.command_tx.clear();
+        self.emergency_stop.signal(());
+    }
+
+    /// Queue a command for the motion task. While the queue is full, keep reporting statuses, so the
+    /// motion task never blocks on the status channel waiting for us, and keep reading, so an M112
+    /// stops the machine straight away rather than waiting its turn. Returns false if one did, in
+    /// which case the command is dropped
+    async fn enqueue(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        pending: &mut Pending<'_>,
+        command: (Option<CommandId>, gcode::Command<AXES>),
+    ) -> Result<bool, Error> {
+        loop {
+            if let Some(gcode::Command::EmergencyStop) = pending.scan() {
+                self.emergency_stop();
+                return Ok(false);
+            }
+            match select3(
+                self.command_tx.send(command),
+                self.status_rx.receive(),
+                pending.read(socket),
+            )
+            .await
+            {
+                Either3::First(()) => return Ok(true),
+                Either3::Second(status) => report_status(socket, status).await?,
+                Either3::Third(res) => res?,
+            }
+        }
+    }
+
+    /// Run a subroutine call or loop, acking it with a single command id that's done once all of it
+    /// is. If it goes wrong part way through, the commands already queued still run, and the error
+    /// is reported instead of the ack. An M0 or M112 sent after it stops it between any two steps
+    async fn run_expansion(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        pending: &mut Pending<'_>,
+        expansion: Expansion<'_>,
+    ) -> Result<(), Error> {
+        for command in expansion {
+            // Let the network have a turn, so a loop that doesn't queue anything can still be
+            // stopped
+            yield_now().await;
+            if socket.can_recv() && pending.has_room() {
+                pending.read(socket).await?;
+            }
+            match pending.scan() {
+                Some(gcode::Command::EmergencyStop) => {
+                    self.emergency_stop();
+                    return report_error(socket, "stopped by M112").await;
+                }
+                Some(_) => {
+                    self.command_tx.clear();
+                    return report_error(socket, "stopped by M0").await;
+                }
+                None => {}
+            }
+
+            match command {
+                Ok(gcode::Command::Stop) => {
+                    self.command_tx.clear();
+                    return socket.write_all(b"(ack)\n").await;
+                }
+                Ok(gcode::Command::EmergencyStop) => {
+                    self.emergency_stop();
+                    return socket.write_all(b"(ack)\n").await;
+                }
+                // Only means anything at the top level, when the machine is locked
+                Ok(gcode::Command::Reset) => {}
+                Ok(gcode::Command::GetFirmwareInfo) => report_firmware_info(socket).await?,
+                Ok(gcode::Command::GetEndstopStatus) => {
+                    report_endstops(socket, self.endstops.read()).await?
+                }
+                Ok(command) => {
+                    if !self.enqueue(socket, pending, (None, command)).await? {
+                        return report_error(socket, "stopped by M112").await;
+                    }
+                }
+                Err(err) => return report_error(socket, err).await,
+            }
+        }
+
+        let command_id = self.gen_command_id();
+        if !self
+            .enqueue(socket, pending, (Some(command_id), gcode::Command::Comment))
+            .await?
+        {
+            return report_error(socket, "stopped by M112").await;
+        }
+        ack(socket, command_id).await
+    }
+
+    async fn handle_command(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        pending: &mut Pending<'_>,
+        subroutines: &mut Subroutines,
+        parameters: &mut gcode::Parameters,
+        command: gcode::Command<AXES>,
+        line: &[u8],
+    ) -> Result<(), Error> {
+        match command {
+            // Goes ahead of everything else, abandoning any block being recorded
+            gcode::Command::EmergencyStop => {
+                subroutines.cancel();
+                self.emergency_stop();
+                return socket.write_all(b"(ack)\n").await;
+            }
+            gcode::Command::Reset => {
+                info!("reset");
+                self.alarm = false;
+                return socket.write_all(b"(ack)\n").await;
+            }
+            gcode::Command::Comment
+            | gcode::Command::GetCurrentPosition
+            | gcode::Command::GetEndstopStatus
+            | gcode::Command::GetFirmwareInfo => {}
+            _ if self.alarm => {
+                return report_error(socket, "locked by an emergency stop, send M999 to reset")
+                    .await;
+            }
+            _ => {}
+        }
+
+        if subroutines.is_recording() {
+            return match subroutines.record(command, line) {
+                Ok(Recorded::Line | Recorded::Subroutine) => socket.write_all(b"(ack)\n").await,
+                Ok(Recorded::Loop) => {
+                    self.run_expansion(socket, pending, subroutines.last_loop(parameters))
+                        .await
+                }
+                Err(err) => report_error(socket, err).await,
+            };
+        }
+
+        let res = match command {
+            gcode::Command::Stop => {
+                // TODO(aspen): Also cancel the current command
+                self.command_tx.clear();
+                Ok(())
+            }
+            gcode::Command::Comment => Ok(()),
+            gcode::Command::GetFirmwareInfo => {
+                report_firmware_info(socket).await?;
+                Ok(())
+            }
+            gcode::Command::GetEndstopStatus => {
+                report_endstops(socket, self.endstops.read()).await?;
+                Ok(())
+            }
+            gcode::Command::SetParameter(number, value) => {
+                parameters.set(number, value);
+                Ok(())
+            }
+            gcode::Command::DefineSubroutine(id) => subroutines.begin(id),
+            gcode::Command::Repeat(id, _) | gcode::Command::While(id, _) => {
+                subroutines.begin_loop(id, line)
+            }
+            gcode::Command::EndSubroutine(_)
+            | gcode::Command::EndRepeat(_)
+            | gcode::Command::EndWhile(_) => Err(program::Error::UnexpectedEnd),
+            gcode::Command::CallSubroutine(id) => {
+                return match subroutines.call(id, parameters) {
+                    Ok(expansion) => self.run_expansion(socket, pending, expansion).await,
+                    Err(err) => report_error(socket, err).await,
+                };
+            }
+            command => {
+                let command_id = self.gen_command_id();
+                if !self
+                    .enqueue(socket, pending, (Some(command_id), command))
+                    .await?
+                {
+                    return report_error(socket, "stopped by M112").await;
+                }
+                return ack(socket, command_id).await;
+            }
+        };
+        match res {
+            Ok(()) => socket.write_all(b"(ack)\n").await,
+            Err(err) => report_error(socket, err).await,
+        }
+    }
+
     pub async fn run(mut self) -> ! {
         let mut rx_buffer = [0; 1024];
         let mut tx_buffer = [0; 1024];
         let mut buf = [0; 2048];
+        // Kept across connections, so a program can call subroutines defined by an earlier one
+        let mut subroutines = Subroutines::new();
+        let mut parameters = gcode::Parameters::new();
 
         'accept: loop {
             let mut socket = TcpSocket::new(self.stack, &mut rx_buffer, &mut tx_buffer);
@@ -43,17 +253,16 @@ impl Server {
                 continue;
             }
 
+            // Lines with line numbers must arrive in sequence, starting from 1 on each connection
+            let mut last_line_number = 0;
+            // A subroutine can't be half-defined by a connection that's gone away
+            subroutines.cancel();
+
             blink_once(&mut self.control).await;
             loop {
                 match select(socket.read(&mut buf[n..]), self.status_rx.receive()).await {
-                    Either::Second(MotionStatusMsg::CommandFinished(CommandId(id))) => {
-                        debug!("Sending status message");
-                        let mut done = [0u8; 64];
-                        {
-                            use embedded_io::Write;
-                            write!(&mut done[..], "(done {id})\n").unwrap();
-                        }
-                        if let Err(e) = socket.write_all(&done).await {
+                    Either::Second(status) => {
+                        if let Err(e) = report_status(&mut socket, status).await {
                             warn!("write error: {}", e);
                         }
                     }
@@ -66,26 +275,60 @@ impl Server {
                             }
                         };
                         debug!("reading command, starting at {}", n);
-                        let command = {
+                        let (line, line_len) = {
                             'read_command: loop {
-                                match gcode::parse_single_command(AXIS_LABELS, &buf[..n]) {
-                                    Ok((remaining, command)) => {
+                                // Lines recorded into a block are evaluated when they're run
+                                let parsed = if subroutines.is_recording() {
+                                    gcode::parse_single_command_unevaluated(AXIS_LABELS, &buf[..n])
+                                } else {
+                                    gcode::parse_single_command_with(
+                                        &parameters,
+                                        AXIS_LABELS,
+                                        &buf[..n],
+                                    )
+                                };
+                                match parsed {
+                                    Ok((remaining, line)) => {
                                         info!("Got command: {}", &buf[..n]);
-                                        let start = usize::try_from(unsafe {
-                                            remaining.as_ptr().offset_from(buf.as_ptr())
-                                        })
-                                        .unwrap();
-                                        let end = start + remaining.len();
-                                        let len = remaining.len();
-                                        buf.copy_within(start..end, 0);
-                                        n = len;
-                                        break 'read_command command;
+                                        break 'read_command (line, n - remaining.len());
                                     }
                                     Err(gcode::Error::Incomplete(_)) => { /* keep reading */ }
-                                    Err(gcode::Error::ParseFailed) => {
-                                        warn!("parse failed");
-                                        if let Err(e) = socket.write_all(b"(parse failed)!\n").await
+                                    Err(
+                                        err @ (gcode::Error::ChecksumMismatch
+                                        | gcode::Error::MissingChecksum),
+                                    ) => {
+                                        warn!("{}", Display2Format(&err));
+                                        // Drop the corrupted line, and ask for it again
+                                        let end = buf[..n]
+                                            .iter()
+                                            .position(|&b| b == b'\n')
+                                            .map_or(n, |i| i + 1);
+                                        buf.copy_within(end..n, 0);
+                                        n -= end;
+                                        if let Err(e) =
+                                            request_resend(&mut socket, last_line_number + 1).await
+                                        {
+                                            warn!("write error: {}", e);
+                                            continue 'accept;
+                                        }
+                                    }
+                                    Err(gcode::Error::ParseFailed(err)) => {
+                                        warn!("parse failed: {}", Display2Format(&err));
+                                        let mut resp_buf = [0u8; 128];
                                         {
+                                            use embedded_io::Write;
+                                            // Truncate the word, so the response always fits
+                                            let word = &err.word[..err.word.len().min(16)];
+                                            writeln!(
+                                                &mut resp_buf[..],
+                                                "(parse failed {} \"{}\" \"{}\")!",
+                                                err.offset,
+                                                word.escape_ascii(),
+                                                err.reason
+                                            )
+                                            .unwrap();
+                                        }
+                                        if let Err(e) = socket.write_all(&resp_buf).await {
                                             warn!("write error: {}", e);
                                             continue 'accept;
                                         }
@@ -108,32 +351,46 @@ impl Server {
                             }
                         };
 
-                        blink_once(&mut self.control).await;
-
-                        match command {
-                            gcode::Command::Stop => {
-                                // TODO(aspen): Also cancel the current command
-                                self.command_tx.clear();
-
-                                if let Err(e) = socket.write_all(b"(ack)\n").await {
+                        if let Some(line_number) = line.number {
+                            if line_number != last_line_number + 1 {
+                                buf.copy_within(line_len..n, 0);
+                                n -= line_len;
+                                warn!(
+                                    "got line {}, expected line {}",
+                                    line_number,
+                                    last_line_number + 1
+                                );
+                                if let Err(e) =
+                                    request_resend(&mut socket, last_line_number + 1).await
+                                {
                                     warn!("write error: {}", e);
                                     continue 'accept;
                                 }
+                                continue;
                             }
-                            command => {
-                                let command_id = self.gen_command_id();
-                                self.command_tx.send((command_id, command)).await;
+                            last_line_number = line_number;
+                        }
 
-                                {
-                                    let mut resp_buf = [0u8; 64];
-                                    use embedded_io::Write;
-                                    resp_buf.fill(0);
-                                    write!(&mut resp_buf[..], "(ack {})\n", command_id.0).unwrap();
-                                    if let Err(e) = socket.write_all(&resp_buf).await {
-                                        warn!("write error: {}", e);
-                                    }
-                                }
-                            }
+                        blink_once(&mut self.control).await;
+
+                        let (text, rest) = buf.split_at_mut(line_len);
+                        let mut pending = Pending::new(rest, n - line_len);
+                        let res = self
+                            .handle_command(
+                                &mut socket,
+                                &mut pending,
+                                &mut subroutines,
+                                &mut parameters,
+                                line.command,
+                                text,
+                            )
+                            .await;
+                        n = line_len + pending.len;
+                        buf.copy_within(line_len..n, 0);
+                        n -= line_len;
+                        if let Err(e) = res {
+                            warn!("write error: {}", e);
+                            continue 'accept;
                         }
                     }
                 }
@@ -141,3 +398,250 @@ impl Server {
         }
     }
 }
+
+/// The lines read after the one being handled, which are handled once it is. They're read ahead
+/// while a subroutine call or loop runs, so that an M0 or M112 doesn't have to wait for it to finish
+struct Pending<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    /// How much of it has been checked for an M0 or M112
+    scanned: usize,
+    /// The subroutine or loop being sent in the lines checked so far, whose lines aren't run yet
+    block: Option<u32>,
+    /// The M0 or M112 found so far, if any. An M112 wins over an M0
+    interrupt: Option<gcode::Command<AXES>>,
+    /// Set once the other end has stopped sending
+    closed: bool,
+}
+
+impl<'a> Pending<'a> {
+    fn new(buf: &'a mut [u8], len: usize) -> Self {
+        Self {
+            buf,
+            len,
+            scanned: 0,
+            block: None,
+            interrupt: None,
+            closed: false,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.len < self.buf.len()
+    }
+
+    /// Wait for more to arrive. Once there's no room left for it, or the other end has stopped
+    /// sending, this never finishes
+    async fn read(&mut self, socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+        if !self.has_room() || self.closed {
+            return future::pending().await;
+        }
+        match socket.read(&mut self.buf[self.len..]).await? {
+            0 => self.closed = true,
+            read => self.len += read,
+        }
+        Ok(())
+    }
+
+    /// Look through the lines read so far for an M0 or M112, which can't wait their turn, returning
+    /// the one found. An M0 inside a subroutine or loop being sent doesn't count, since it isn't
+    /// run yet
+    fn scan(&mut self) -> Option<gcode::Command<AXES>> {
+        while let Some(end) = self.buf[self.scanned..self.len]
+            .iter()
+            .position(|&b| b == b'\n')
+        {
+            let text = &self.buf[self.scanned..self.scanned + end + 1];
+            self.scanned += end + 1;
+            // A line that doesn't parse is reported when it's handled
+            let Ok((_, line)) = gcode::parse_single_command_unevaluated(AXIS_LABELS, text) else {
+                continue;
+            };
+            match (self.block, line.command) {
+                (_, gcode::Command::EmergencyStop) => self.interrupt = Some(line.command),
+                (None, gcode::Command::Stop) => {
+                    self.interrupt.get_or_insert(line.command);
+                }
+                (
+                    None,
+                    gcode::Command::DefineSubroutine(id)
+                    | gcode::Command::Repeat(id, _)
+                    | gcode::Command::While(id, _),
+                ) => self.block = Some(id),
+                (Some(id), gcode::Command::EndSubroutine(end))
+                    if end.is_none_or(|end| end == id) =>
+                {
+                    self.block = None
+                }
+                (Some(id), gcode::Command::EndRepeat(end) | gcode::Command::EndWhile(end))
+                    if end == id =>
+                {
+                    self.block = None
+                }
+                _ => {}
+            }
+        }
+        self.interrupt
+    }
+}
+
+async fn ack(socket: &mut TcpSocket<'_>, command_id: CommandId) -> Result<(), Error> {
+    let mut resp_buf = [0u8; 64];
+    {
+        use embedded_io::Write;
+        writeln!(&mut resp_buf[..], "(ack {})", command_id.0).unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
+
+async fn report_status(socket: &mut TcpSocket<'_>, status: MotionStatusMsg) -> Result<(), Error> {
+    match status {
+        MotionStatusMsg::CommandFinished(CommandId(id)) => {
+            debug!("Sending status message");
+            let mut done = [0u8; 64];
+            {
+                use embedded_io::Write;
+                writeln!(&mut done[..], "(done {id})").unwrap();
+            }
+            socket.write_all(&done).await
+        }
+        MotionStatusMsg::CommandFailed(id, reason) => {
+            debug!("Sending failure");
+            let mut failed = [0u8; 128];
+            {
+                use embedded_io::Write;
+                match id {
+                    Some(CommandId(id)) => writeln!(&mut failed[..], "(failed {id} \"{reason}\")"),
+                    None => writeln!(&mut failed[..], "(failed \"{reason}\")"),
+                }
+                .unwrap();
+            }
+            socket.write_all(&failed).await
+        }
+        MotionStatusMsg::Position(position) => {
+            debug!("Sending position");
+            report_position(socket, position).await
+        }
+        MotionStatusMsg::StepsPerUnit(steps) => {
+            debug!("Sending steps per unit");
+            report_steps_per_unit(socket, steps).await
+        }
+    }
+}
+
+/// Report a line that parsed, but couldn't be acted on, as `(error "message")`
+async fn report_error(
+    socket: &mut TcpSocket<'_>,
+    err: impl fmt::Display + Format,
+) -> Result<(), Error> {
+    warn!("{}", err);
+    let mut resp_buf = [0u8; 128];
+    {
+        use embedded_io::Write;
+        writeln!(&mut resp_buf[..], "(error \"{err}\")").unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
+
+async fn request_resend(socket: &mut TcpSocket<'_>, line_number: u32) -> Result<(), Error> {
+    let mut resp_buf = [0u8; 64];
+    {
+        use embedded_io::Write;
+        writeln!(&mut resp_buf[..], "(resend {line_number})").unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
+
+/// Report the limit switches as `(endstops (X triggered) (Z open))`, leaving out axes without one
+async fn report_endstops(
+    socket: &mut TcpSocket<'_>,
+    endstops: [Option<bool>; AXES],
+) -> Result<(), Error> {
+    let mut resp_buf = [0u8; 128];
+    {
+        use embedded_io::Write;
+        let mut w = &mut resp_buf[..];
+        write!(w, "(endstops").unwrap();
+        for (label, triggered) in AXIS_LABELS.iter().zip(endstops) {
+            if let Some(triggered) = triggered {
+                let state = if triggered { "triggered" } else { "open" };
+                write!(w, " ({label} {state})").unwrap();
+            }
+        }
+        writeln!(w, ")").unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
+
+/// Report the calibration of each axis as `(steps-per-unit (X 83.333) (Z 166.666) (C 3200) (units
+/// mm))`
+async fn report_steps_per_unit(
+    socket: &mut TcpSocket<'_>,
+    steps: StepsPerUnit,
+) -> Result<(), Error> {
+    let mut resp_buf = [0u8; 128];
+    {
+        use embedded_io::Write;
+        let mut w = &mut resp_buf[..];
+        write!(w, "(steps-per-unit").unwrap();
+        for (label, steps) in AXIS_LABELS.iter().zip(steps.steps) {
+            write!(w, " ({label} {steps})").unwrap();
+        }
+        writeln!(w, " (units {}))", units_name(steps.units)).unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
+
+/// Report what the firmware is and what it supports, as eg `(firmware (name "coil-winder")
+/// (version "0.1.0") (build "release") (axes X Z C) (units mm in) (buffer 32) (codes G0 G1 ...))`
+async fn report_firmware_info(socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+    let build = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+    let mut resp_buf = [0u8; 384];
+    let capacity = resp_buf.len();
+    let len = {
+        use embedded_io::Write;
+        let mut w = &mut resp_buf[..];
+        write!(
+            w,
+            "(firmware (name \"{}\") (version \"{}\") (build \"{build}\") (axes",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+        )
+        .unwrap();
+        for label in AXIS_LABELS {
+            write!(w, " {label}").unwrap();
+        }
+        write!(w, ") (units mm in) (buffer {COMMAND_BUFFER_SIZE}) (codes").unwrap();
+        for code in gcode::SUPPORTED_CODES {
+            write!(w, " {code}").unwrap();
+        }
+        writeln!(w, "))").unwrap();
+        capacity - w.len()
+    };
+    socket.write_all(&resp_buf[..len]).await
+}
+
+/// Report the position as `(position (X 1.5) (Z 0) (C 2) (F 20) (units mm))`
+async fn report_position(socket: &mut TcpSocket<'_>, position: Position) -> Result<(), Error> {
+    let mut resp_buf = [0u8; 192];
+    {
+        use embedded_io::Write;
+        let mut w = &mut resp_buf[..];
+        write!(w, "(position").unwrap();
+        for (label, coord) in AXIS_LABELS.iter().zip(position.coords) {
+            write!(w, " ({label} {coord})").unwrap();
+        }
+        writeln!(
+            w,
+            " (F {}) (units {}))",
+            position.feedrate,
+            position.units_name()
+        )
+        .unwrap();
+    }
+    socket.write_all(&resp_buf).await
+}
@@ -1,21 +1,21 @@
 .program steps
-main:
-    pull block    ; osr := steps
-    mov x, osr    ; x   := osr (steps)
-    pull block    ; osr := sleeps_per_step
-    out pins, 1   ; write direction bit (LSB of speed)
-    set pins, 0   ; reset pins
-    jmp x-- loop  ; decrement loop counter at start of loop (loops are always do
-                  ; while)
-    jmp end       ; skip the loop if x is 0
-loop:
-    mov y, osr    ; y   := osr (sleeps_per_step)
-    set pins, 1   ; send pulse
-    ;; note we've set up the clock such that the cycle time is equal to the
-    ;; intended pulse width (2 μs)
-    set pins, 0   ; drop pulse
-sleep:            ; sleep for y cycles
-    jmp y-- sleep
-    jmp x-- loop  ; loop again
+;; Steps every axis together, on one clock. Each word is one step of the move
+;; (LSB first): the direction pins, 6 bits; the pins to write with the step
+;; pins pulsed, 6 bits; and the cycles to sleep before the next step, 20 bits.
+;; The step and direction pins of all the axes are the out pins
+.wrap_target
+step:
+    out y, 6      ; y   := the direction pins, with every step pin low
+    mov pins, y   ; write the direction before stepping
+    out pins, 6   ; send pulse, on the axes that step
+    out x, 20     ; x   := sleeps until the next step
+    ;; note we've set up the clock such that the pulse, three cycles long, is
+    ;; 6 μs wide
+    jmp !x end    ; a step with no sleep ends the move
+    mov pins, y   ; drop pulse
+sleep:            ; sleep for x cycles
+    jmp x-- sleep
+    jmp step      ; on to the next step, without stopping
 end:
     irq 0 rel     ; done; re-sync with firmware
+.wrap
@@ -1,39 +1,90 @@
 use core::time::Duration;
 
-use fixed::{types::extra::U10, FixedU32};
+use fixed::{types::extra::U10, FixedI32, FixedU32};
 
-// TODO(aspen): Consider making this signed after all, in case we want to rotate the spindle
-// backwards(?)
 pub type UCoord = FixedU32<U10>;
 
+/// Coordinates are signed, so that relative moves can go backwards (including rotating the spindle
+/// backwards, for counter-wound coils)
+pub type ICoord = FixedI32<U10>;
+
+/// A coordinate for each axis label, or `None` for the axes a command leaves out
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub struct UPos<const AXES: usize>(pub [Option<UCoord>; AXES]);
+pub struct Pos<const AXES: usize>(pub [Option<ICoord>; AXES]);
 
-impl<const AXES: usize> From<[Option<UCoord>; AXES]> for UPos<AXES> {
-    fn from(coordinates: [Option<UCoord>; AXES]) -> Self {
+impl<const AXES: usize> From<[Option<ICoord>; AXES]> for Pos<AXES> {
+    fn from(coordinates: [Option<ICoord>; AXES]) -> Self {
         Self(coordinates)
     }
 }
 
-impl<const AXES: usize> From<[UCoord; AXES]> for UPos<AXES> {
-    fn from(coordinates: [UCoord; AXES]) -> Self {
+impl<const AXES: usize> From<[ICoord; AXES]> for Pos<AXES> {
+    fn from(coordinates: [ICoord; AXES]) -> Self {
         Self(coordinates.map(Some))
     }
 }
 
+/// The words of a G0 or G1 move
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Move<const AXES: usize> {
+    /// Where to move to (or how far to move, in relative mode), one coordinate per axis label
+    pub target: Pos<AXES>,
+    /// F
+    pub feedrate: Option<UCoord>,
+}
+
+impl<const AXES: usize> From<Pos<AXES>> for Move<AXES> {
+    fn from(target: Pos<AXES>) -> Self {
+        Self {
+            target,
+            feedrate: None,
+        }
+    }
+}
+
+/// How the coordinates in moves are interpreted
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DistanceMode {
+    /// Coordinates are positions, relative to the origin
+    #[default]
+    Absolute,
+    /// Coordinates are distances, relative to the current position
+    Relative,
+}
+
+/// The units of linear coordinates and feedrates. Rotational axes aren't affected
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Units {
+    Inches,
+    #[default]
+    Millimeters,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Command<const AXES: usize> {
+    /// A line with no command on it - either blank, or containing only comments
+    Comment,
+
     // G-codes
     /// G0
-    RapidMove(UPos<AXES>),
+    RapidMove(Move<AXES>),
     /// G1
-    LinearMove(UPos<AXES>),
+    LinearMove(Move<AXES>),
     /// G4
     Dwell(Duration),
-    /// G27
-    Park(Option<UPos<AXES>>),
-    /// G28
-    Home,
+    /// G20, G21
+    SetUnits(Units),
+    /// G27 - park at the given coordinates, or at the park position if none are given
+    Park(Option<Pos<AXES>>),
+    /// G27.1 - set the park position of the given axes, or of every axis to wherever it is now if
+    /// none are given
+    SetParkPosition(Option<Pos<AXES>>),
+    /// G28 - the axes to home, or all of them if none are given
+    Home([bool; AXES]),
+    /// G90, G91
+    SetDistanceMode(DistanceMode),
+    /// G92 - declare the current position to be the given coordinates, without moving
+    SetPosition(Pos<AXES>),
 
     // M-codes
     /// M0
@@ -42,6 +93,56 @@ pub enum Command<const AXES: usize> {
     EnableAllSteppers,
     /// M18
     DisableAllSteppers,
+    /// M92 - set how many steps each axis takes per unit (per millimeter or inch, or per rotation
+    /// for rotational axes), or report them if no axes are given
+    SetStepsPerUnit(Pos<AXES>),
+    /// M201 - limit how quickly each of the given axes can accelerate, in units per second squared
+    SetMaxAcceleration(Pos<AXES>),
+    /// M203 - limit how fast each of the given axes can move, in units per second
+    SetMaxFeedrate(Pos<AXES>),
+    /// M204 S - how quickly moves accelerate, in terms of the feedrate
+    SetAcceleration(UCoord),
+    /// M112 - stop all motion immediately, and lock the machine until a [`Command::Reset`]
+    EmergencyStop,
     /// M114
     GetCurrentPosition,
+    /// M115 - report what the firmware is, and what it supports
+    GetFirmwareInfo,
+    /// M119 - report which limit switches are triggered
+    GetEndstopStatus,
+    /// M400 - done once every command before it is done, so a host can wait for the machine to
+    /// finish moving
+    WaitForMotion,
+    /// M999 - unlock the machine after an [`Command::EmergencyStop`]
+    Reset,
+
+    // O-words
+    /// `O<n> sub` - the lines up to the matching [`Command::EndSubroutine`] are the body of
+    /// subroutine n
+    DefineSubroutine(u32),
+    /// `O<n> endsub`, or M99 - the end of a subroutine's body, returning to wherever it was called
+    /// from
+    EndSubroutine(Option<u32>),
+    /// `O<n> call`, or `M98 P<n>`
+    CallSubroutine(u32),
+    /// `O<n> repeat [count]` - run the lines up to `O<n> endrepeat` count times
+    Repeat(u32, u32),
+    /// `O<n> endrepeat`
+    EndRepeat(u32),
+    /// `O<n> while [condition]` - run the lines up to `O<n> endwhile` for as long as the condition
+    /// (anything but zero) holds. The condition is checked again every time this line is run
+    While(u32, bool),
+    /// `O<n> endwhile`
+    EndWhile(u32),
+
+    /// `#<n> = <value>`
+    SetParameter(u32, ICoord),
+}
+
+/// A single line of gcode, as sent over the wire
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Line<const AXES: usize> {
+    /// The line number, if the line started with an `N` word
+    pub number: Option<u32>,
+    pub command: Command<AXES>,
 }
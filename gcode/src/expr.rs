@@ -0,0 +1,305 @@
+//! Numbered parameters (`#1`), and bracketed expressions (`[#1 * 2]`), which can stand in for the
+//! numbers in a command
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag_no_case,
+    character::complete::{self, space0},
+    combinator::{cut, map_res, value},
+    sequence::{delimited, preceded},
+    Parser,
+};
+
+use crate::{
+    ast::ICoord,
+    parser::{icoord, Error, IResult},
+    Reason,
+};
+
+/// How deeply brackets, function calls and signs can nest in an expression, so that the firmware
+/// can't run out of stack parsing one
+pub const MAX_DEPTH: usize = 16;
+
+/// One more than the highest parameter number, so parameters are `#1` up to `#99`
+pub const PARAMETERS: usize = 100;
+
+/// The values of the numbered parameters. Parameters start out unset, and using one before it's been
+/// set is an error
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Parameters {
+    values: [Option<ICoord>; PARAMETERS],
+    /// Whether to actually compute values. If not, only the syntax of expressions is checked
+    evaluate: bool,
+}
+
+impl Parameters {
+    pub const fn new() -> Self {
+        Self {
+            values: [None; PARAMETERS],
+            evaluate: true,
+        }
+    }
+
+    /// For parsing lines that are going to be evaluated later: every value comes out as zero, and
+    /// nothing (like an unset parameter, or dividing by zero) is an error unless it's a syntax error
+    pub(crate) const UNEVALUATED: Self = Self {
+        values: [None; PARAMETERS],
+        evaluate: false,
+    };
+
+    pub fn get(&self, number: u32) -> Option<ICoord> {
+        self.values.get(number as usize).copied().flatten()
+    }
+
+    /// Set a parameter. The parser never produces numbers outside of `1..PARAMETERS`, so anything
+    /// else is ignored
+    pub fn set(&mut self, number: u32, value: ICoord) {
+        if let Some(parameter) = self.values.get_mut(number as usize) {
+            *parameter = Some(value);
+        }
+    }
+
+    /// A value computed for the word at `i`, failing there if it couldn't be
+    pub(crate) fn computed<'a, T: Default>(
+        &self,
+        i: &'a [u8],
+        value: Result<T, Reason>,
+    ) -> Result<T, nom::Err<Error<'a>>> {
+        match value {
+            Ok(value) => Ok(value),
+            Err(_) if !self.evaluate => Ok(T::default()),
+            Err(reason) => Err(nom::Err::Failure(Error::new(i, reason))),
+        }
+    }
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The number of a parameter, after its `#`
+pub fn parameter_number(i: &[u8]) -> IResult<'_, u32> {
+    let (rest, number) = preceded(
+        complete::char('#'),
+        cut(map_res(complete::digit1, u32::from_ascii)),
+    )
+    .parse(i)?;
+    if !(1..PARAMETERS as u32).contains(&number) {
+        return Err(nom::Err::Failure(Error::new(i, Reason::UnknownParameter)));
+    }
+    Ok((rest, number))
+}
+
+fn parameter<'a>(params: &Parameters, i: &'a [u8]) -> IResult<'a, ICoord> {
+    let (rest, number) = parameter_number(i)?;
+    let value = params.get(number).ok_or(Reason::UndefinedParameter);
+    Ok((rest, params.computed(i, value)?))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Op {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    And,
+    Or,
+    Xor,
+}
+
+fn truth(b: bool) -> ICoord {
+    if b {
+        ICoord::ONE
+    } else {
+        ICoord::ZERO
+    }
+}
+
+impl Op {
+    fn apply(self, lhs: ICoord, rhs: ICoord) -> Result<ICoord, Reason> {
+        let checked = |res: Option<ICoord>| res.ok_or(Reason::NumberOutOfRange);
+        match self {
+            Op::Mul => checked(lhs.checked_mul(rhs)),
+            Op::Div | Op::Mod if rhs == ICoord::ZERO => Err(Reason::DivisionByZero),
+            Op::Div => checked(lhs.checked_div(rhs)),
+            Op::Mod => checked(lhs.checked_rem_euclid(rhs)),
+            Op::Add => checked(lhs.checked_add(rhs)),
+            Op::Sub => checked(lhs.checked_sub(rhs)),
+            Op::Eq => Ok(truth(lhs == rhs)),
+            Op::Ne => Ok(truth(lhs != rhs)),
+            Op::Gt => Ok(truth(lhs > rhs)),
+            Op::Ge => Ok(truth(lhs >= rhs)),
+            Op::Lt => Ok(truth(lhs < rhs)),
+            Op::Le => Ok(truth(lhs <= rhs)),
+            Op::And => Ok(truth(lhs != ICoord::ZERO && rhs != ICoord::ZERO)),
+            Op::Or => Ok(truth(lhs != ICoord::ZERO || rhs != ICoord::ZERO)),
+            Op::Xor => Ok(truth((lhs != ICoord::ZERO) != (rhs != ICoord::ZERO))),
+        }
+    }
+}
+
+/// One or more operands, separated by operators of the same precedence, evaluated left to right
+fn binary<'a>(
+    params: &Parameters,
+    i: &'a [u8],
+    operand: impl Fn(&'a [u8]) -> IResult<'a, ICoord>,
+    operator: impl Fn(&'a [u8]) -> IResult<'a, Op>,
+) -> IResult<'a, ICoord> {
+    let (mut i, mut lhs) = operand(i)?;
+    loop {
+        let (op_start, _) = space0(i)?;
+        let (rest, op) = match operator(op_start) {
+            Err(nom::Err::Error(_)) => return Ok((i, lhs)),
+            res => res?,
+        };
+        let (rest, rhs) = cut(preceded(space0, &operand)).parse(rest)?;
+        lhs = params.computed(op_start, op.apply(lhs, rhs))?;
+        i = rest;
+    }
+}
+
+/// A number, parameter, bracketed expression or function call, optionally negated
+fn atom<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    if depth > MAX_DEPTH {
+        return Err(nom::Err::Failure(Error::new(i, Reason::NestedTooDeeply)));
+    }
+    match i.first() {
+        Some(&sign @ (b'-' | b'+')) => {
+            // Numbers take their own sign, so they can be as negative as an ICoord can go
+            if let Ok(res) = icoord(i) {
+                return Ok(res);
+            }
+            let (rest, value) =
+                cut(preceded(space0, |i| atom(params, depth + 1, i))).parse(&i[1..])?;
+            let value = match sign {
+                b'+' => Some(value),
+                _ => value.checked_neg(),
+            };
+            return Ok((
+                rest,
+                params.computed(i, value.ok_or(Reason::NumberOutOfRange))?,
+            ));
+        }
+        Some(b'#') => return parameter(params, i),
+        Some(b'[') => return bracketed_expression(params, depth + 1, i),
+        _ => {}
+    }
+    let function: IResult<'_, fn(ICoord) -> Option<ICoord>> = alt((
+        value(
+            ICoord::checked_abs as fn(ICoord) -> Option<ICoord>,
+            tag_no_case("ABS"),
+        ),
+        value(ICoord::checked_floor as fn(_) -> _, tag_no_case("FIX")),
+        value(ICoord::checked_ceil as fn(_) -> _, tag_no_case("FUP")),
+        value(ICoord::checked_round as fn(_) -> _, tag_no_case("ROUND")),
+    ))
+    .parse(i);
+    if let Ok((rest, function)) = function {
+        let (rest, arg) = cut(|i| bracketed_expression(params, depth + 1, i)).parse(rest)?;
+        let value = function(arg).ok_or(Reason::NumberOutOfRange);
+        return Ok((rest, params.computed(i, value)?));
+    }
+    icoord(i)
+}
+
+fn product<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    binary(
+        params,
+        i,
+        |i| atom(params, depth, i),
+        |i| {
+            alt((
+                value(Op::Mul, complete::char('*')),
+                value(Op::Div, complete::char('/')),
+                value(Op::Mod, tag_no_case("MOD")),
+            ))
+            .parse(i)
+        },
+    )
+}
+
+fn sum<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    binary(
+        params,
+        i,
+        |i| product(params, depth, i),
+        |i| {
+            alt((
+                value(Op::Add, complete::char('+')),
+                value(Op::Sub, complete::char('-')),
+            ))
+            .parse(i)
+        },
+    )
+}
+
+fn comparison<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    binary(
+        params,
+        i,
+        |i| sum(params, depth, i),
+        |i| {
+            alt((
+                value(Op::Eq, tag_no_case("EQ")),
+                value(Op::Ne, tag_no_case("NE")),
+                value(Op::Gt, tag_no_case("GT")),
+                value(Op::Ge, tag_no_case("GE")),
+                value(Op::Lt, tag_no_case("LT")),
+                value(Op::Le, tag_no_case("LE")),
+            ))
+            .parse(i)
+        },
+    )
+}
+
+fn logical<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    binary(
+        params,
+        i,
+        |i| comparison(params, depth, i),
+        |i| {
+            alt((
+                value(Op::And, tag_no_case("AND")),
+                value(Op::Or, tag_no_case("OR")),
+                value(Op::Xor, tag_no_case("XOR")),
+            ))
+            .parse(i)
+        },
+    )
+}
+
+fn bracketed_expression<'a>(params: &Parameters, depth: usize, i: &'a [u8]) -> IResult<'a, ICoord> {
+    preceded(
+        complete::char('['),
+        cut(delimited(
+            space0,
+            |i| logical(params, depth, i),
+            (space0, complete::char(']')),
+        )),
+    )
+    .parse(i)
+}
+
+/// An expression in brackets, eg `[#2 + #1 * 3]`. Operators have the usual precedence, with
+/// comparisons (`EQ`, `LT`, ...) below arithmetic and `AND`, `OR` and `XOR` below those. Comparisons
+/// and logical operators give 1 for true and 0 for false, and anything other than 0 counts as true
+pub fn bracketed(params: &Parameters) -> impl Fn(&[u8]) -> IResult<'_, ICoord> {
+    move |i| bracketed_expression(params, 0, i)
+}
+
+/// A parameter or a bracketed expression, where a number could go
+pub fn expression(params: &Parameters) -> impl Fn(&[u8]) -> IResult<'_, ICoord> {
+    move |i| match i.first() {
+        Some(b'#') => parameter(params, i),
+        _ => bracketed_expression(params, 0, i),
+    }
+}
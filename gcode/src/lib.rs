@@ -1,24 +1,492 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![feature(int_from_ascii)]
 
 mod ast;
+mod expr;
 mod parser;
+#[cfg(any(test, feature = "std"))]
+mod program;
+mod writer;
 
-pub use ast::{Command, UCoord, UPos};
-use nom::{character::streaming::newline, sequence::terminated, Parser};
+pub use ast::{Command, DistanceMode, ICoord, Line, Move, Pos, UCoord, Units};
+pub use expr::{Parameters, PARAMETERS};
+pub use parser::SUPPORTED_CODES;
+#[cfg(any(test, feature = "std"))]
+pub use program::{parse_program, ProgramError, ProgramLine};
+pub use writer::CommandDisplay;
 
-pub enum Error {
-    ParseFailed,
+use core::fmt;
+
+use nom::{
+    character::{complete, streaming::newline},
+    combinator::{map, opt},
+    sequence::terminated,
+    Parser,
+};
+
+/// Why a line failed to parse
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reason {
+    /// Something that doesn't belong where it is
+    UnexpectedWord,
+    UnknownGCode,
+    UnknownMCode,
+    /// A move without any axes to move
+    MissingAxis,
+    /// The same word appearing twice in one command
+    RepeatedWord,
+    BadNumber,
+    NumberOutOfRange,
+    /// A parameter number other than `#1` up to `#99`
+    UnknownParameter,
+    /// A parameter used before it was set
+    UndefinedParameter,
+    DivisionByZero,
+    /// An expression with too many levels of brackets
+    NestedTooDeeply,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::UnexpectedWord => "unexpected word",
+            Reason::UnknownGCode => "unknown G-code",
+            Reason::UnknownMCode => "unknown M-code",
+            Reason::MissingAxis => "missing axis",
+            Reason::RepeatedWord => "repeated word",
+            Reason::BadNumber => "bad number",
+            Reason::NumberOutOfRange => "number out of range",
+            Reason::UnknownParameter => "unknown parameter",
+            Reason::UndefinedParameter => "undefined parameter",
+            Reason::DivisionByZero => "division by zero",
+            Reason::NestedTooDeeply => "expression nested too deeply",
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ParseError<'a> {
+    /// Byte offset of the error from the start of the line
+    pub offset: usize,
+    /// The word (up to the next whitespace or comment) the error was found at
+    pub word: &'a [u8],
+    pub reason: Reason,
+}
+
+impl<'a> ParseError<'a> {
+    fn new(line: &'a [u8], error: parser::Error<'a>) -> Self {
+        let rest = error.input;
+        let word_len = rest
+            .iter()
+            .position(|c| matches!(c, b' ' | b'\t' | b'\r' | b'\n' | b'(' | b';'))
+            .unwrap_or(rest.len());
+        Self {
+            offset: line.len() - rest.len(),
+            word: &rest[..word_len],
+            reason: error.reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} \"{}\" at byte {}",
+            self.reason,
+            self.word.escape_ascii(),
+            self.offset
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<'a> {
+    ParseFailed(ParseError<'a>),
+    /// The line's checksum didn't match its contents, so it was corrupted on the way
+    ChecksumMismatch,
+    /// The line has a line number, but no checksum to check it arrived intact
+    MissingChecksum,
     Incomplete(nom::Needed),
 }
 
+impl fmt::Display for Error<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ParseFailed(e) => e.fmt(f),
+            Error::ChecksumMismatch => f.write_str("checksum mismatch"),
+            Error::MissingChecksum => f.write_str("missing checksum"),
+            Error::Incomplete(_) => f.write_str("incomplete line"),
+        }
+    }
+}
+
+/// Compute the checksum of a line of gcode, as the xor of all its bytes.
+///
+/// A line with a checksum is sent as `N<line> <command>*<checksum>`, where the checksum covers
+/// everything before the `*`
+pub fn checksum(line: &[u8]) -> u8 {
+    line.iter().fold(0, |acc, b| acc ^ b)
+}
+
+/// Check a line's checksum before parsing any of it, since a line that's been corrupted on the way
+/// usually won't parse either, and should be sent again rather than rejected. If `numbered` is set,
+/// a line with a line number has to have a checksum
+fn verify_checksum(input: &[u8], numbered: bool) -> Result<(), Error<'_>> {
+    let line = input.split(|&c| c == b'\n').next().unwrap_or_default();
+    // The checksum is the first `*` that isn't in a comment or an expression
+    let mut in_comment = false;
+    let mut brackets = 0usize;
+    for (i, &c) in line.iter().enumerate() {
+        match c {
+            b'(' => in_comment = true,
+            b')' => in_comment = false,
+            b';' if !in_comment => break,
+            b'[' if !in_comment => brackets += 1,
+            b']' if !in_comment => brackets = brackets.saturating_sub(1),
+            b'*' if !in_comment && brackets == 0 => {
+                let digits = line[i + 1..]
+                    .iter()
+                    .position(|c| !c.is_ascii_digit())
+                    .map_or(&line[i + 1..], |end| &line[i + 1..i + 1 + end]);
+                return match u8::from_ascii(digits) {
+                    Ok(expected) if expected == checksum(&line[..i]) => Ok(()),
+                    _ => Err(Error::ChecksumMismatch),
+                };
+            }
+            _ => {}
+        }
+    }
+    let has_number = (opt(parser::separator), parser::line_number)
+        .parse(line)
+        .is_ok();
+    if numbered && has_number {
+        return Err(Error::MissingChecksum);
+    }
+    Ok(())
+}
+
+/// Parse a line, without any parameters set
 pub fn parse_single_command<const AXES: usize>(
     axis_labels: [char; AXES],
     input: &[u8],
-) -> Result<(&[u8], Command<AXES>), Error> {
-    match terminated(parser::command(axis_labels), newline).parse(input) {
-        Ok((i, res)) => Ok((i, res)),
+) -> Result<(&[u8], Line<AXES>), Error<'_>> {
+    static NO_PARAMETERS: Parameters = Parameters::new();
+    parse_single_command_with(&NO_PARAMETERS, axis_labels, input)
+}
+
+/// Parse a line without evaluating any of its parameters or expressions, which all come out as zero.
+/// For lines that are stored to be run later, when the parameters they use could be different
+pub fn parse_single_command_unevaluated<const AXES: usize>(
+    axis_labels: [char; AXES],
+    input: &[u8],
+) -> Result<(&[u8], Line<AXES>), Error<'_>> {
+    parse_single_command_with(&Parameters::UNEVALUATED, axis_labels, input)
+}
+
+/// Parse a line, evaluating any parameters and expressions in it with the given parameter values.
+/// Assignments (`#1 = 2`) come back as [`Command::SetParameter`], for the caller to apply.
+///
+/// Lines with a line number have to have a checksum, and any line with a checksum that doesn't
+/// match fails with [`Error::ChecksumMismatch`], however badly it's been mangled
+pub fn parse_single_command_with<'a, const AXES: usize>(
+    parameters: &Parameters,
+    axis_labels: [char; AXES],
+    input: &'a [u8],
+) -> Result<(&'a [u8], Line<AXES>), Error<'a>> {
+    parse_line(parameters, axis_labels, input, true)
+}
+
+/// Parse a line, only requiring a checksum for lines with line numbers if `numbered` is set. A
+/// program read from a file can number its lines without checksumming them
+pub(crate) fn parse_line<'a, const AXES: usize>(
+    parameters: &Parameters,
+    axis_labels: [char; AXES],
+    input: &'a [u8],
+    numbered: bool,
+) -> Result<(&'a [u8], Line<AXES>), Error<'a>> {
+    // Only ever parse whole lines, so that errors always point at the actual problem rather than the
+    // end of the input
+    if !input.contains(&b'\n') {
+        return Err(Error::Incomplete(nom::Needed::Unknown));
+    }
+    verify_checksum(input, numbered)?;
+
+    let body = (
+        opt(parser::separator),
+        opt(terminated(parser::line_number, opt(parser::separator))),
+        map(opt(parser::command(axis_labels, parameters)), |command| {
+            command.unwrap_or(Command::Comment)
+        }),
+        opt(parser::separator),
+    );
+    let mut line = (
+        body,
+        // Already checked
+        opt(terminated(parser::checksum, opt(parser::separator))),
+        opt(parser::line_comment),
+        opt(complete::char('\r')),
+        newline,
+    );
+    match line.parse(input) {
+        Ok((i, ((_, number, command, _), _, _, _, _))) => Ok((i, Line { number, command })),
         Err(nom::Err::Incomplete(needed)) => Err(Error::Incomplete(needed)),
-        Err(_) => Err(Error::ParseFailed),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+            Err(Error::ParseFailed(ParseError::new(input, e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XZC: [char; 3] = ['X', 'Z', 'C'];
+
+    fn parse_line(input: &[u8]) -> Option<Line<3>> {
+        match parse_single_command(XZC, input) {
+            Ok((rem, line)) => {
+                assert_eq!(rem, b"");
+                Some(line)
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn parse(input: &[u8]) -> Option<Command<3>> {
+        parse_line(input).map(|line| line.command)
+    }
+
+    #[test]
+    fn blank_lines() {
+        assert_eq!(parse(b"\n"), Some(Command::Comment));
+        assert_eq!(parse(b"  \t \n"), Some(Command::Comment));
+    }
+
+    #[test]
+    fn comment_lines() {
+        assert_eq!(parse(b"(wind the first layer)\n"), Some(Command::Comment));
+        assert_eq!(parse(b"; wind the first layer\n"), Some(Command::Comment));
+        assert_eq!(
+            parse(b"(first) (layer) ; wind it\n"),
+            Some(Command::Comment)
+        );
+    }
+
+    #[test]
+    fn trailing_comments() {
+        assert_eq!(parse(b"M17 ; wake up\n"), Some(Command::EnableAllSteppers));
+        assert_eq!(parse(b"M17;wake up\n"), Some(Command::EnableAllSteppers));
+        assert_eq!(parse(b"M17 (wake up)\n"), Some(Command::EnableAllSteppers));
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        assert_eq!(parse(b"M17 (wake up\n"), None);
+        assert!(matches!(
+            parse_single_command(XZC, b"M17 (wake"),
+            Err(Error::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn leading_whitespace() {
+        assert_eq!(parse(b"  M17\n"), Some(Command::EnableAllSteppers));
+        let line = b"\tN3 M17";
+        let input = [&line[..], format!("*{}\n", checksum(line)).as_bytes()].concat();
+        assert_eq!(parse(&input), Some(Command::EnableAllSteppers));
+    }
+
+    #[test]
+    fn crlf() {
+        assert_eq!(parse(b"M17\r\n"), Some(Command::EnableAllSteppers));
+        assert_eq!(
+            parse(b"M17 ; wake up\r\n"),
+            Some(Command::EnableAllSteppers)
+        );
+        assert_eq!(parse(b"\r\n"), Some(Command::Comment));
+
+        let line = b"N12 M17";
+        let input = [&line[..], format!("*{}\r\n", checksum(line)).as_bytes()].concat();
+        assert_eq!(parse(&input), Some(Command::EnableAllSteppers));
+    }
+
+    #[test]
+    fn line_numbers() {
+        let line = b"N12 M17";
+        let input = [&line[..], format!("*{}\n", checksum(line)).as_bytes()].concat();
+        assert_eq!(
+            parse_line(&input),
+            Some(Line {
+                number: Some(12),
+                command: Command::EnableAllSteppers
+            })
+        );
+        assert_eq!(
+            parse_line(b"M17\n"),
+            Some(Line {
+                number: None,
+                command: Command::EnableAllSteppers
+            })
+        );
+    }
+
+    #[test]
+    fn valid_checksum() {
+        let line = b"N12 M17";
+        let input = [&line[..], format!("*{}\n", checksum(line)).as_bytes()].concat();
+        assert_eq!(
+            parse_line(&input),
+            Some(Line {
+                number: Some(12),
+                command: Command::EnableAllSteppers
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let line = b"N12 M17";
+        let input = [&line[..], format!("*{}\n", checksum(line) ^ 1).as_bytes()].concat();
+        assert!(matches!(
+            parse_single_command(XZC, &input),
+            Err(Error::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn corrupted_line() {
+        // Mangled so badly it wouldn't parse, but it's the checksum that counts
+        let line = b"N12 M17";
+        let input = format!("N12 \x7f17*{}\n", checksum(line));
+        assert!(matches!(
+            parse_single_command(XZC, input.as_bytes()),
+            Err(Error::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            parse_single_command(XZC, b"N12 M17*\n"),
+            Err(Error::ChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn missing_checksum() {
+        assert!(matches!(
+            parse_single_command(XZC, b"N12 M17\n"),
+            Err(Error::MissingChecksum)
+        ));
+        assert!(matches!(
+            parse_single_command(XZC, b"N12 M17 ; *12\n"),
+            Err(Error::MissingChecksum)
+        ));
+    }
+
+    #[test]
+    fn checksum_after_expression() {
+        let line = b"N12 G1 X[2 * 3]";
+        let input = [&line[..], format!("*{}\n", checksum(line)).as_bytes()].concat();
+        assert_eq!(
+            parse(&input),
+            Some(Command::LinearMove(Move {
+                target: Pos([Some(ICoord::from_num(6)), None, None]),
+                feedrate: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn checksum_before_comment() {
+        let line = b"N12 M17";
+        let input = [
+            &line[..],
+            format!("*{} ; wake up\n", checksum(line)).as_bytes(),
+        ]
+        .concat();
+        assert_eq!(parse(&input), Some(Command::EnableAllSteppers));
+    }
+
+    fn parse_error(input: &[u8]) -> ParseError<'_> {
+        match parse_single_command(XZC, input) {
+            Err(Error::ParseFailed(e)) => e,
+            res => panic!("expected parse failure, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_codes() {
+        let e = parse_error(b"G5 X1\n");
+        assert_eq!(e.reason, Reason::UnknownGCode);
+        assert_eq!(e.word, b"G5");
+        assert_eq!(e.offset, 0);
+
+        let e = parse_error(b"M170\n");
+        assert_eq!(e.reason, Reason::UnknownMCode);
+        assert_eq!(e.word, b"M170");
+    }
+
+    #[test]
+    fn missing_axis() {
+        let e = parse_error(b"G1 ; nowhere\n");
+        assert_eq!(e.reason, Reason::MissingAxis);
+        assert_eq!(e.word, b"G1");
+        assert_eq!(e.offset, 0);
+    }
+
+    #[test]
+    fn bad_numbers() {
+        let e = parse_error(b"G1 X1 Z1.2.3\n");
+        assert_eq!(e.reason, Reason::UnexpectedWord);
+        assert_eq!(e.word, b".3");
+        assert_eq!(e.offset, 10);
+
+        let e = parse_error(b"G1 X1 Zfoo\n");
+        assert_eq!(e.reason, Reason::BadNumber);
+        assert_eq!(e.word, b"Zfoo");
+        assert_eq!(e.offset, 6);
+
+        let e = parse_error(b"G1 C9999999\n");
+        assert_eq!(e.reason, Reason::NumberOutOfRange);
+        assert_eq!(e.word, b"C9999999");
+        assert_eq!(e.offset, 3);
+    }
+
+    #[test]
+    fn unexpected_words() {
+        let e = parse_error(b"M17 (wake up) X1\n");
+        assert_eq!(e.reason, Reason::UnexpectedWord);
+        assert_eq!(e.word, b"X1");
+        assert_eq!(e.offset, 14);
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            parse_error(b"G1 Zfoo\n").to_string(),
+            "bad number \"Zfoo\" at byte 3"
+        );
+    }
+
+    #[test]
+    fn unevaluated() {
+        // Checked for syntax, but not evaluated
+        let (_, line) = parse_single_command_unevaluated(XZC, b"G1 X[#5 / 0] F#6\n").unwrap();
+        assert_eq!(
+            line.command,
+            Command::LinearMove(Move {
+                target: Pos([Some(ICoord::ZERO), None, None]),
+                feedrate: Some(UCoord::ZERO),
+            })
+        );
+        assert!(matches!(
+            parse_single_command_unevaluated(XZC, b"G1 X[#5 /]\n"),
+            Err(Error::ParseFailed(_))
+        ));
+        assert!(matches!(
+            parse_single_command(XZC, b"G1 X#5\n"),
+            Err(Error::ParseFailed(ParseError {
+                reason: Reason::UndefinedParameter,
+                ..
+            }))
+        ));
     }
 }
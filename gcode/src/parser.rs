@@ -2,256 +2,1251 @@
 
 use core::time::Duration;
 
-use heapless::Vec;
+use fixed::ParseFixedError;
 use nom::{
     branch::alt,
-    bytes::{complete::take_while1, streaming::tag},
-    character::{complete::multispace1, streaming::char},
-    combinator::{map, map_res, opt, value},
-    error::ErrorKind,
-    number::complete::recognize_float,
-    sequence::preceded,
-    AsChar, IResult, Parser,
+    bytes::{
+        complete::{take_till, take_while1},
+        streaming::tag,
+    },
+    character::{
+        complete::{self, alpha1, space0, space1},
+        streaming::{char, digit1},
+    },
+    combinator::{cut, map, map_res, not, opt, recognize, value},
+    error::{ErrorKind, FromExternalError, ParseError},
+    multi::many1_count,
+    sequence::{delimited, preceded},
+    AsChar, Parser,
 };
 
-use crate::ast::{Command, UCoord, UPos};
+use crate::{
+    ast::{Command, DistanceMode, ICoord, Move, Pos, UCoord, Units},
+    expr::{self, Parameters},
+    Reason,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Error<'a> {
+    pub input: &'a [u8],
+    pub reason: Reason,
+}
+
+impl<'a> Error<'a> {
+    pub fn new(input: &'a [u8], reason: Reason) -> Self {
+        Self { input, reason }
+    }
+}
+
+impl<'a> ParseError<&'a [u8]> for Error<'a> {
+    fn from_error_kind(input: &'a [u8], _kind: ErrorKind) -> Self {
+        Self::new(input, Reason::UnexpectedWord)
+    }
+
+    fn append(_input: &'a [u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        // Prefer whichever error knows more about what went wrong, and then whichever error got
+        // further into the input
+        match (self.reason, other.reason) {
+            (Reason::UnexpectedWord, reason) if reason != Reason::UnexpectedWord => other,
+            (reason, Reason::UnexpectedWord) if reason != Reason::UnexpectedWord => self,
+            _ if other.input.len() < self.input.len() => other,
+            _ => self,
+        }
+    }
+}
 
-pub fn ucoord(i: &[u8]) -> IResult<&[u8], UCoord> {
-    let (i, txt) = recognize_float(i)?;
-    let num = UCoord::from_ascii(txt).unwrap();
-    Ok((i, num))
+/// [`map_res`] is only used for numbers
+impl<'a, E> FromExternalError<&'a [u8], E> for Error<'a> {
+    fn from_external_error(input: &'a [u8], _kind: ErrorKind, _e: E) -> Self {
+        Self::new(input, Reason::BadNumber)
+    }
 }
 
-pub fn labeled_ucoord(label: char) -> impl Fn(&[u8]) -> IResult<&[u8], UCoord> {
+pub type IResult<'a, O> = nom::IResult<&'a [u8], O, Error<'a>>;
+
+fn fail<O>(input: &[u8], reason: Reason) -> IResult<'_, O> {
+    Err(nom::Err::Error(Error::new(input, reason)))
+}
+
+/// An inline comment, in parentheses. Can appear anywhere whitespace can
+pub fn comment(i: &[u8]) -> IResult<'_, &[u8]> {
+    recognize((
+        complete::char('('),
+        take_till(|c| c == b')' || c == b'\n'),
+        char(')'),
+    ))
+    .parse(i)
+}
+
+/// A comment that runs until the end of the line, starting with a semicolon
+pub fn line_comment(i: &[u8]) -> IResult<'_, &[u8]> {
+    recognize((complete::char(';'), take_till(|c| c == b'\n'))).parse(i)
+}
+
+/// Whitespace between words, including any inline comments
+pub fn separator(i: &[u8]) -> IResult<'_, &[u8]> {
+    recognize(many1_count(alt((space1, comment)))).parse(i)
+}
+
+/// The letter at the start of a word, in either case
+fn letter(c: char) -> impl Fn(&[u8]) -> IResult<'_, char> {
+    move |i| complete::satisfy(|l| l.eq_ignore_ascii_case(&c)).parse(i)
+}
+
+/// A line number, eg `N123`
+pub fn line_number(i: &[u8]) -> IResult<'_, u32> {
+    preceded(letter('N'), map_res(digit1, u32::from_ascii)).parse(i)
+}
+
+/// A checksum at the end of a line, eg `*71`
+pub fn checksum(i: &[u8]) -> IResult<'_, u8> {
+    preceded(char('*'), map_res(digit1, u8::from_ascii)).parse(i)
+}
+
+type FromAscii<N> = fn(&[u8]) -> Result<(N, bool), ParseFixedError>;
+
+/// The text of a decimal number, eg `-1.5`, `2.` or `.25`. Unlike
+/// [`recognize_float`](nom::number::complete::recognize_float), there's no exponent, since `E` is
+/// a word of its own in gcode
+fn decimal(i: &[u8]) -> IResult<'_, &[u8]> {
+    recognize((
+        opt(complete::one_of("+-")),
+        alt((
+            recognize((
+                complete::digit1,
+                opt((complete::char('.'), complete::digit0)),
+            )),
+            recognize((complete::char('.'), complete::digit1)),
+        )),
+    ))
+    .parse(i)
+}
+
+/// A fixed-point number, which must fit in the given type
+fn fixed<N>(i: &[u8], overflowing_from_ascii: FromAscii<N>) -> IResult<'_, N> {
+    let (rest, txt) = decimal(i).map_err(|e| e.map(|_| Error::new(i, Reason::BadNumber)))?;
+    match overflowing_from_ascii(txt) {
+        Ok((num, false)) => Ok((rest, num)),
+        Ok((_, true)) => fail(i, Reason::NumberOutOfRange),
+        Err(_) => fail(i, Reason::BadNumber),
+    }
+}
+
+pub fn ucoord(i: &[u8]) -> IResult<'_, UCoord> {
+    fixed(i, UCoord::overflowing_from_ascii)
+}
+
+/// A number, either written out or computed by a parameter or [`expr::expression`]. `from_icoord`
+/// converts a computed value, returning None if it doesn't fit
+fn number<N: Default>(
+    params: &Parameters,
+    literal: fn(&[u8]) -> IResult<'_, N>,
+    from_icoord: fn(ICoord) -> Option<N>,
+) -> impl Fn(&[u8]) -> IResult<'_, N> {
+    move |i| match literal(i) {
+        Err(nom::Err::Error(e)) => match expr::expression(params)(i) {
+            Ok((rest, value)) => {
+                let n = from_icoord(value).ok_or(Reason::NumberOutOfRange);
+                Ok((rest, params.computed(i, n)?))
+            }
+            Err(nom::Err::Error(_)) => Err(nom::Err::Error(e)),
+            Err(e) => Err(e),
+        },
+        res => res,
+    }
+}
+
+/// Computed counts and ids are rounded to the nearest whole number
+fn round_to_u32(value: ICoord) -> Option<u32> {
+    value.checked_round()?.checked_to_num()
+}
+
+fn round_to_u64(value: ICoord) -> Option<u64> {
+    value.checked_round()?.checked_to_num()
+}
+
+/// Once we've seen the label, the number after it has to be valid
+fn labeled<O>(
+    label: char,
+    number: impl Fn(&[u8]) -> IResult<'_, O>,
+) -> impl Fn(&[u8]) -> IResult<'_, O> {
     move |i| {
-        let (i, _) = char(label)(i)?;
-        ucoord(i)
+        let (rest, _) = letter(label)(i)?;
+        number(rest).map_err(|e| match e {
+            // Point at the whole word, rather than just the number
+            nom::Err::Error(e) => nom::Err::Failure(Error::new(i, e.reason)),
+            e => e,
+        })
     }
 }
 
-pub fn upos<const AXES: usize>(
+pub fn labeled_ucoord(label: char, params: &Parameters) -> impl Fn(&[u8]) -> IResult<'_, UCoord> {
+    labeled(label, number(params, ucoord, UCoord::checked_from_num))
+}
+
+pub fn icoord(i: &[u8]) -> IResult<'_, ICoord> {
+    fixed(i, ICoord::overflowing_from_ascii)
+}
+
+pub fn labeled_icoord(label: char, params: &Parameters) -> impl Fn(&[u8]) -> IResult<'_, ICoord> {
+    labeled(label, number(params, icoord, Some))
+}
+
+/// Zero or more words (optionally separated by whitespace or comments), in any order. `set` records
+/// each word, returning whether it had already been seen - words can only appear once
+fn words<'a, W>(
+    mut i: &'a [u8],
+    word: impl Fn(&'a [u8]) -> IResult<'a, W>,
+    mut set: impl FnMut(W) -> bool,
+) -> IResult<'a, ()> {
+    loop {
+        let word_start = match separator(i) {
+            Err(nom::Err::Error(_)) => i,
+            res => res?.0,
+        };
+        let (rest, w) = match word(word_start) {
+            Err(nom::Err::Error(_)) => return Ok((i, ())),
+            res => res?,
+        };
+        if set(w) {
+            return Err(nom::Err::Failure(Error::new(
+                word_start,
+                Reason::RepeatedWord,
+            )));
+        }
+        i = rest;
+    }
+}
+
+/// A coordinate for one of the axes, along with the index of that axis
+fn axis_word<const AXES: usize>(
     coord_labels: [char; AXES],
-) -> impl Fn(&[u8]) -> IResult<&[u8], UPos<AXES>> {
-    move |mut i| {
-        let mut res = Vec::<_, AXES>::new();
-        for c in coord_labels {
-            let coord;
-            (i, coord) = opt(preceded(
-                take_while1(|c| c == b' ' || c == b'\t'),
-                labeled_ucoord(c),
-            ))
-            .parse(i)?;
-            res.push(coord).unwrap();
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, (usize, ICoord)> {
+    move |i| {
+        for (axis, label) in coord_labels.into_iter().enumerate() {
+            match labeled_icoord(label, params)(i) {
+                Err(nom::Err::Error(_)) => continue,
+                res => return res.map(|(i, coord)| (i, (axis, coord))),
+            }
         }
-        Ok((i, UPos::from(res.into_array().unwrap())))
+        fail(i, Reason::UnexpectedWord)
     }
 }
 
-pub fn non_empty_upos<const AXES: usize>(
+pub fn position<const AXES: usize>(
     coord_labels: [char; AXES],
-) -> impl Fn(&[u8]) -> IResult<&[u8], UPos<AXES>> {
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Pos<AXES>> {
     move |i| {
-        let (i, pos) = upos(coord_labels)(i)?;
+        let mut pos = Pos([None; AXES]);
+        let (i, ()) = words(i, axis_word(coord_labels, params), |(axis, coord)| {
+            pos.0[axis].replace(coord).is_some()
+        })?;
+        Ok((i, pos))
+    }
+}
+
+pub fn non_empty_position<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Pos<AXES>> {
+    move |i| {
+        let (i, pos) = position(coord_labels, params)(i)?;
         if !pos.0.iter().any(Option::is_some) {
-            return Err(nom::Err::Error(nom::error::make_error(
-                i,
-                ErrorKind::NonEmpty,
-            )));
+            return Err(nom::Err::Failure(Error::new(i, Reason::MissingAxis)));
         }
         Ok((i, pos))
     }
 }
 
-pub fn g(code: &str) -> impl Fn(&[u8]) -> IResult<&[u8], ()> {
+/// Any character that can be part of a number
+fn number_char(i: &[u8]) -> IResult<'_, char> {
+    complete::satisfy(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+')).parse(i)
+}
+
+pub fn g(code: &str) -> impl Fn(&[u8]) -> IResult<'_, ()> {
     move |i| {
-        let (i, _) = char('G')(i)?;
+        let (i, _) = letter('G')(i)?;
         let (i, _) = tag(code)(i)?;
+        let (i, _) = not(complete::satisfy(|c| c.is_ascii_digit())).parse(i)?;
         Ok((i, ()))
     }
 }
 
-pub fn m(code: &str) -> impl Fn(&[u8]) -> IResult<&[u8], ()> {
+pub fn m(code: &str) -> impl Fn(&[u8]) -> IResult<'_, ()> {
     move |i| {
-        let (i, _) = char('M')(i)?;
+        let (i, _) = letter('M')(i)?;
         let (i, _) = tag(code)(i)?;
+        let (i, _) = not(complete::satisfy(|c| c.is_ascii_digit())).parse(i)?;
         Ok((i, ()))
     }
 }
 
-pub fn upos_g_command<const AXES: usize>(
+pub fn position_g_command<const AXES: usize>(
     g_code: &str,
     coord_labels: [char; AXES],
-    mk_command: impl Fn(UPos<AXES>) -> Command<AXES>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], Command<AXES>> {
+    params: &Parameters,
+    mk_command: impl Fn(Pos<AXES>) -> Command<AXES>,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
     move |i| {
         let (i, _) = g(g_code)(i)?;
-        let (i, _) = multispace1(i)?;
-        let (i, pos) = upos(coord_labels)(i)?;
+        let (i, pos) = position(coord_labels, params)(i)?;
         Ok((i, mk_command(pos)))
     }
 }
 
-pub fn non_empty_upos_g_command<const AXES: usize>(
+pub fn non_empty_position_g_command<const AXES: usize>(
     g_code: &str,
     coord_labels: [char; AXES],
-    mk_command: impl Fn(UPos<AXES>) -> Command<AXES>,
-) -> impl Fn(&[u8]) -> IResult<&[u8], Command<AXES>> {
+    params: &Parameters,
+    mk_command: impl Fn(Pos<AXES>) -> Command<AXES>,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
     move |i| {
-        let (i, _) = g(g_code)(i)?;
-        let (i, pos) = non_empty_upos(coord_labels)(i)?;
-        Ok((i, mk_command(pos)))
+        let (rest, _) = g(g_code)(i)?;
+        let (rest, pos) = non_empty_position(coord_labels, params)(rest).map_err(|e| {
+            e.map(|e| match e.reason {
+                // Point at the command, rather than wherever the axes would've been
+                Reason::MissingAxis => Error::new(i, Reason::MissingAxis),
+                _ => e,
+            })
+        })?;
+        Ok((rest, mk_command(pos)))
     }
 }
 
-pub fn dwell<const AXES: usize>(i: &[u8]) -> IResult<&[u8], Command<AXES>> {
-    let (i, _) = g("4")(i)?;
-    let (i, _) = multispace1(i)?;
-    let (i, dur) = alt((
-        preceded(
-            char('S'),
-            map(
-                map_res(take_while1(AsChar::is_dec_digit), u64::from_ascii),
-                Duration::from_secs,
-            ),
-        ),
-        preceded(
-            char('P'),
+/// A single word of a move: either a coordinate for one of the axes, or a feedrate
+enum MoveWord {
+    Axis(usize, ICoord),
+    Feedrate(UCoord),
+}
+
+fn move_word<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, MoveWord> {
+    move |i| {
+        alt((
+            map(axis_word(coord_labels, params), |(axis, coord)| {
+                MoveWord::Axis(axis, coord)
+            }),
+            map(labeled_ucoord('F', params), MoveWord::Feedrate),
+        ))
+        .parse(i)
+    }
+}
+
+/// A move, with its words in any order. There has to be at least one word
+pub fn move_command<const AXES: usize>(
+    g_code: &str,
+    coord_labels: [char; AXES],
+    params: &Parameters,
+    mk_command: impl Fn(Move<AXES>) -> Command<AXES>,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (rest, _) = g(g_code)(i)?;
+        let mut mv = Move::from(Pos([None; AXES]));
+        let (rest, ()) = words(rest, move_word(coord_labels, params), |word| match word {
+            MoveWord::Axis(axis, coord) => mv.target.0[axis].replace(coord).is_some(),
+            MoveWord::Feedrate(feedrate) => mv.feedrate.replace(feedrate).is_some(),
+        })?;
+
+        if mv.target.0.iter().all(Option::is_none) && mv.feedrate.is_none() {
+            // Point at the command, rather than wherever the axes would've been
+            return Err(nom::Err::Failure(Error::new(i, Reason::MissingAxis)));
+        }
+        Ok((rest, mk_command(mv)))
+    }
+}
+
+/// G27, optionally with a position to park at
+pub fn park<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = g("27")(i)?;
+        let (i, pos) = position(coord_labels, params)(i)?;
+        let pos = pos.0.iter().any(Option::is_some).then_some(pos);
+        Ok((i, Command::Park(pos)))
+    }
+}
+
+/// G27.1, optionally with the park position to set
+pub fn set_park_position<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = g("27")(i)?;
+        let (i, _) = (complete::char('.'), complete::char('1')).parse(i)?;
+        let (i, _) = not(complete::satisfy(|c| c.is_ascii_digit())).parse(i)?;
+        let (i, pos) = position(coord_labels, params)(i)?;
+        let pos = pos.0.iter().any(Option::is_some).then_some(pos);
+        Ok((i, Command::SetParkPosition(pos)))
+    }
+}
+
+/// G28, optionally followed by the labels of the axes to home. Any values after them are ignored
+pub fn home<const AXES: usize>(
+    coord_labels: [char; AXES],
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = g("28")(i)?;
+        let mut axes = [false; AXES];
+        let (i, ()) = words(
+            i,
+            |i| {
+                for (axis, label) in coord_labels.into_iter().enumerate() {
+                    // Hosts often send `G28 X0`, but the value doesn't mean anything
+                    let value = opt(decimal);
+                    if let Ok((i, _)) = (letter(label), value, not(number_char)).parse(i) {
+                        return Ok((i, axis));
+                    }
+                }
+                fail(i, Reason::UnexpectedWord)
+            },
+            |axis| core::mem::replace(&mut axes[axis], true),
+        )?;
+        Ok((i, Command::Home(axes)))
+    }
+}
+
+/// Values for any of the axes which, like steps per unit or speed limits, have to be more than zero
+pub fn positive_position<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Pos<AXES>> {
+    move |i| {
+        let mut pos = Pos([None; AXES]);
+        let (i, ()) = words(
+            i,
+            |i| {
+                let (rest, (axis, value)) = axis_word(coord_labels, params)(i)?;
+                Ok((rest, (axis, params.computed(i, positive(value))?)))
+            },
+            |(axis, value)| pos.0[axis].replace(value).is_some(),
+        )?;
+        Ok((i, pos))
+    }
+}
+
+fn positive<N: PartialOrd + Default>(value: N) -> Result<N, Reason> {
+    if value > N::default() {
+        Ok(value)
+    } else {
+        Err(Reason::NumberOutOfRange)
+    }
+}
+
+/// M92, optionally followed by the steps per unit for any of the axes
+pub fn steps_per_unit<const AXES: usize>(
+    coord_labels: [char; AXES],
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = m("92")(i)?;
+        let (i, steps) = positive_position(coord_labels, params)(i)?;
+        Ok((i, Command::SetStepsPerUnit(steps)))
+    }
+}
+
+/// An M-code setting a limit for at least one of the axes, like M203
+pub fn limits<const AXES: usize>(
+    m_code: &str,
+    coord_labels: [char; AXES],
+    params: &Parameters,
+    mk_command: impl Fn(Pos<AXES>) -> Command<AXES>,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (rest, _) = m(m_code)(i)?;
+        let (rest, pos) = positive_position(coord_labels, params)(rest)?;
+        if !pos.0.iter().any(Option::is_some) {
+            return Err(nom::Err::Failure(Error::new(i, Reason::MissingAxis)));
+        }
+        Ok((rest, mk_command(pos)))
+    }
+}
+
+/// M204 S<acceleration>
+pub fn acceleration<const AXES: usize>(
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = m("204")(i)?;
+        let (i, _) = opt(separator).parse(i)?;
+        let (rest, acceleration) = cut(labeled_ucoord('S', params)).parse(i)?;
+        let acceleration = params.computed(i, positive(acceleration))?;
+        Ok((rest, Command::SetAcceleration(acceleration)))
+    }
+}
+
+fn millis(i: &[u8]) -> IResult<'_, u64> {
+    map_res(take_while1(AsChar::is_dec_digit), u64::from_ascii).parse(i)
+}
+
+/// Seconds can be fractional, down to the millisecond
+fn secs_to_duration(secs: UCoord) -> Duration {
+    let millis = (secs.frac() * UCoord::from_num(1000)).round();
+    Duration::from_secs(secs.to_num()) + Duration::from_millis(millis.to_num())
+}
+
+pub fn dwell<const AXES: usize>(
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = g("4")(i)?;
+        let (i, _) = opt(separator).parse(i)?;
+        let (i, dur) = cut(alt((
+            map(labeled_ucoord('S', params), secs_to_duration),
             map(
-                map_res(take_while1(AsChar::is_dec_digit), u64::from_ascii),
+                labeled('P', number(params, millis, round_to_u64)),
                 Duration::from_millis,
             ),
-        ),
-    ))
-    .parse(i)?;
-    Ok((i, Command::Dwell(dur)))
+        )))
+        .parse(i)?;
+        Ok((i, Command::Dwell(dur)))
+    }
+}
+
+fn uint(i: &[u8]) -> IResult<'_, u32> {
+    map_res(complete::digit1, u32::from_ascii).parse(i)
+}
+
+/// `M98 P<n>`, calling subroutine n
+pub fn call<const AXES: usize>(
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, _) = m("98")(i)?;
+        let (i, _) = opt(separator).parse(i)?;
+        let (i, id) = cut(labeled('P', number(params, uint, round_to_u32))).parse(i)?;
+        Ok((i, Command::CallSubroutine(id)))
+    }
+}
+
+/// A loop count, eg `[12]` or `[#1 * 2]`. A whole number on its own is read as it is, so a count can
+/// go higher than an expression can
+fn count(params: &Parameters) -> impl Fn(&[u8]) -> IResult<'_, u32> {
+    move |i| {
+        let mut literal = delimited(
+            (complete::char('['), space0),
+            uint,
+            (space0, complete::char(']')),
+        );
+        match literal.parse(i) {
+            Err(nom::Err::Error(_)) => {
+                let (rest, count) = expr::bracketed(params)(i)?;
+                let count = round_to_u32(count).ok_or(Reason::NumberOutOfRange);
+                Ok((rest, params.computed(i, count)?))
+            }
+            res => res,
+        }
+    }
 }
 
+/// An O-word, eg `O100 call`
+pub fn o_command<const AXES: usize>(
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, id) = preceded(letter('O'), cut(uint)).parse(i)?;
+        let (i, _) = opt(separator).parse(i)?;
+        let (rest, keyword) = cut(alpha1).parse(i)?;
+        let is = |word: &str| keyword.eq_ignore_ascii_case(word.as_bytes());
+        if is("sub") {
+            Ok((rest, Command::DefineSubroutine(id)))
+        } else if is("endsub") {
+            Ok((rest, Command::EndSubroutine(Some(id))))
+        } else if is("call") {
+            Ok((rest, Command::CallSubroutine(id)))
+        } else if is("repeat") {
+            let (rest, _) = opt(separator).parse(rest)?;
+            let (rest, count) = cut(count(params)).parse(rest)?;
+            Ok((rest, Command::Repeat(id, count)))
+        } else if is("endrepeat") {
+            Ok((rest, Command::EndRepeat(id)))
+        } else if is("while") {
+            let (rest, _) = opt(separator).parse(rest)?;
+            let (rest, condition) = cut(expr::bracketed(params)).parse(rest)?;
+            Ok((rest, Command::While(id, condition != ICoord::ZERO)))
+        } else if is("endwhile") {
+            Ok((rest, Command::EndWhile(id)))
+        } else {
+            Err(nom::Err::Failure(Error::new(i, Reason::UnexpectedWord)))
+        }
+    }
+}
+
+/// `#<n> = <value>`, setting a parameter
+pub fn assignment<const AXES: usize>(
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
+    move |i| {
+        let (i, parameter) = expr::parameter_number(i)?;
+        let (i, _) = cut((space0, complete::char('='), space0)).parse(i)?;
+        let (i, value) = cut(number(params, icoord, Some)).parse(i)?;
+        Ok((i, Command::SetParameter(parameter, value)))
+    }
+}
+
+/// Every G- and M-code that [`command`] understands
+pub const SUPPORTED_CODES: &[&str] = &[
+    "G0", "G1", "G4", "G20", "G21", "G27", "G27.1", "G28", "G90", "G91", "G92", "M0", "M17", "M18",
+    "M92", "M98", "M99", "M112", "M114", "M115", "M119", "M201", "M203", "M204", "M400", "M999",
+];
+
 pub fn command<const AXES: usize>(
     coord_labels: [char; AXES],
-) -> impl Fn(&[u8]) -> IResult<&[u8], Command<AXES>> {
+    params: &Parameters,
+) -> impl Fn(&[u8]) -> IResult<'_, Command<AXES>> {
     move |i| {
-        alt((
-            non_empty_upos_g_command("0", coord_labels, Command::RapidMove),
-            non_empty_upos_g_command("1", coord_labels, Command::LinearMove),
-            dwell,
-            value(Command::Stop, m("0")),
-            value(Command::EnableAllSteppers, m("17")),
-            value(Command::DisableAllSteppers, m("18")),
-            value(Command::Home, g("28")),
-            value(Command::GetCurrentPosition, m("114")),
+        let res = alt((
+            alt((
+                move_command("0", coord_labels, params, Command::RapidMove),
+                move_command("1", coord_labels, params, Command::LinearMove),
+                dwell(params),
+                value(Command::SetUnits(Units::Inches), g("20")),
+                value(Command::SetUnits(Units::Millimeters), g("21")),
+                // Before G27, which would take the G27 and leave the .1
+                set_park_position(coord_labels, params),
+                park(coord_labels, params),
+                home(coord_labels),
+                value(Command::SetDistanceMode(DistanceMode::Absolute), g("90")),
+                value(Command::SetDistanceMode(DistanceMode::Relative), g("91")),
+                non_empty_position_g_command("92", coord_labels, params, Command::SetPosition),
+            )),
+            alt((
+                value(Command::Stop, m("0")),
+                value(Command::EnableAllSteppers, m("17")),
+                value(Command::DisableAllSteppers, m("18")),
+                steps_per_unit(coord_labels, params),
+                limits("201", coord_labels, params, Command::SetMaxAcceleration),
+                limits("203", coord_labels, params, Command::SetMaxFeedrate),
+                acceleration(params),
+                call(params),
+                value(Command::EndSubroutine(None), m("99")),
+                value(Command::EmergencyStop, m("112")),
+                value(Command::GetCurrentPosition, m("114")),
+                value(Command::GetFirmwareInfo, m("115")),
+                value(Command::GetEndstopStatus, m("119")),
+                value(Command::WaitForMotion, m("400")),
+                value(Command::Reset, m("999")),
+            )),
+            o_command(params),
+            assignment(params),
         ))
-        .parse(i)
+        .parse(i);
+
+        match res {
+            Err(nom::Err::Error(e)) if e.reason == Reason::UnexpectedWord => {
+                // If this looks like a G- or M-code, it's just one we don't know about
+                let code = (complete::one_of("GMgm"), complete::digit1::<_, Error>).parse(i);
+                match code {
+                    Ok((_, ('G' | 'g', _))) => {
+                        Err(nom::Err::Failure(Error::new(i, Reason::UnknownGCode)))
+                    }
+                    Ok((_, _)) => Err(nom::Err::Failure(Error::new(i, Reason::UnknownMCode))),
+                    Err(_) => Err(nom::Err::Error(e)),
+                }
+            }
+            res => res,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use fixed::FixedU32;
+    use fixed::FixedI32;
 
     use super::*;
 
     const XYZ: [char; 3] = ['X', 'Y', 'Z'];
-    const XYZF: [char; 4] = ['X', 'Y', 'Z', 'F'];
-    const XZCF: [char; 4] = ['X', 'Z', 'C', 'F'];
+    const XZC: [char; 3] = ['X', 'Z', 'C'];
+    const NO_PARAMS: &Parameters = &Parameters::new();
 
     #[test]
-    fn non_empty_upos_requires_non_empty_coords() {
-        let result = non_empty_upos(XYZ).parse(b"");
+    fn non_empty_position_requires_non_empty_coords() {
+        let result = non_empty_position(XYZ, NO_PARAMS).parse(b"");
         assert!(result.is_err());
     }
 
     #[test]
     fn g0() {
-        let (remaining, res) = command(XYZ)(b"G0 X90.6 Y13.8 Z22.4").unwrap();
+        let (remaining, res) = command(XYZ, NO_PARAMS)(b"G0 X90.6 Y13.8 Z22.4").unwrap();
         assert_eq!(remaining, b"");
         assert_eq!(
             res,
-            Command::RapidMove(UPos([
-                Some(FixedU32::from_str("90.6").unwrap()),
-                Some(FixedU32::from_str("13.8").unwrap()),
-                Some(FixedU32::from_str("22.4").unwrap()),
-            ]))
+            Command::RapidMove(
+                Pos([
+                    Some(FixedI32::from_str("90.6").unwrap()),
+                    Some(FixedI32::from_str("13.8").unwrap()),
+                    Some(FixedI32::from_str("22.4").unwrap()),
+                ])
+                .into()
+            )
         )
     }
 
     #[test]
     fn g0_incomplete() {
-        let (remaining, res) = command(XYZ)(b"G0 X90.6").unwrap();
+        let (remaining, res) = command(XYZ, NO_PARAMS)(b"G0 X90.6").unwrap();
         assert_eq!(remaining, b"");
         assert_eq!(
             res,
-            Command::RapidMove(UPos([
-                Some(FixedU32::from_str("90.6").unwrap()),
-                None,
-                None,
-            ]))
+            Command::RapidMove(Pos([Some(FixedI32::from_str("90.6").unwrap()), None, None]).into())
         )
     }
 
     #[test]
     fn g0_feedrate() {
-        let (remaining, res) = command(XYZF)(b"G0 F1500").unwrap();
+        let (remaining, res) = command(XYZ, NO_PARAMS)(b"G0 F1500").unwrap();
         assert_eq!(remaining, b"");
         assert_eq!(
             res,
-            Command::RapidMove(UPos([None, None, None, Some(FixedU32::from_num(1500))]))
+            Command::RapidMove(Move {
+                target: Pos([None; 3]),
+                feedrate: Some(UCoord::from_num(1500)),
+            })
         );
     }
 
     #[test]
     fn zc_axis() {
-        let (rem, res) = command(XZCF)(b"G0 Z40 C10 F40").unwrap();
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G0 Z40 C10 F40").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(
             res,
-            Command::RapidMove(UPos([
-                None,
-                Some(FixedU32::lit("40")),
-                Some(FixedU32::lit("10")),
-                Some(FixedU32::lit("40"))
-            ]))
+            Command::RapidMove(Move {
+                target: Pos([None, Some(FixedI32::lit("40")), Some(FixedI32::lit("10"))]),
+                feedrate: Some(UCoord::lit("40")),
+            })
+        );
+    }
+
+    #[test]
+    fn words_in_any_order() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G1 F40 C10 Z40").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::LinearMove(Move {
+                target: Pos([None, Some(FixedI32::lit("40")), Some(FixedI32::lit("10"))]),
+                feedrate: Some(UCoord::lit("40")),
+            })
+        );
+    }
+
+    #[test]
+    fn repeated_words() {
+        let res = command(XZC, NO_PARAMS)(b"G1 Z40 C10 Z41");
+        assert_eq!(
+            res,
+            Err(nom::Err::Failure(Error::new(b"Z41", Reason::RepeatedWord)))
+        );
+        let res = command(XZC, NO_PARAMS)(b"G1 F40 F41");
+        assert_eq!(
+            res,
+            Err(nom::Err::Failure(Error::new(b"F41", Reason::RepeatedWord)))
         );
     }
 
     #[test]
     fn g4_secs() {
-        let (rem, res) = command(XYZF)(b"G4 S4").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"G4 S4").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(res, Command::Dwell(Duration::from_secs(4)));
     }
 
     #[test]
     fn g4_millis() {
-        let (rem, res) = command(XYZF)(b"G4 P123").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"G4 P123").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(res, Command::Dwell(Duration::from_millis(123)));
     }
 
     #[test]
     fn m0_stop() {
-        let (rem, res) = command(XYZF)(b"M0").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M0").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(res, Command::Stop);
     }
 
+    #[test]
+    fn m112_emergency_stop() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M112").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::EmergencyStop);
+
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M999").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::Reset);
+    }
+
+    #[test]
+    fn m92_steps_per_unit() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M92 X83.333 Z400").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::SetStepsPerUnit(Pos([
+                Some(ICoord::from_str("83.333").unwrap()),
+                None,
+                Some(ICoord::from_num(400)),
+            ]))
+        );
+
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M92").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::SetStepsPerUnit(Pos([None; 3])));
+
+        for input in [&b"M92 X0"[..], b"M92 Y-80"] {
+            let Err(nom::Err::Failure(e)) = command(XYZ, NO_PARAMS)(input) else {
+                panic!("expected {input:?} to fail");
+            };
+            assert_eq!(e.reason, Reason::NumberOutOfRange);
+            assert_eq!(e.input, &input[4..]);
+        }
+    }
+
+    #[test]
+    fn m201_m203_m204_limits() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M201 X500 Y1000").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::SetMaxAcceleration(Pos([
+                Some(ICoord::from_num(500)),
+                Some(ICoord::from_num(1000)),
+                None,
+            ]))
+        );
+
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M203 Z2.5").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::SetMaxFeedrate(Pos([None, None, Some(ICoord::lit("2.5"))]))
+        );
+
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M204 S200").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::SetAcceleration(UCoord::from_num(200)));
+
+        let error = |input: &'static [u8]| match command(XYZ, NO_PARAMS)(input) {
+            Err(nom::Err::Failure(e)) => e.reason,
+            res => panic!("expected {input:?} to fail, got {res:?}"),
+        };
+        assert_eq!(error(b"M201"), Reason::MissingAxis);
+        assert_eq!(error(b"M203 X0"), Reason::NumberOutOfRange);
+        assert_eq!(error(b"M204 S0"), Reason::NumberOutOfRange);
+        assert_eq!(error(b"M204"), Reason::UnexpectedWord);
+    }
+
+    #[test]
+    fn m400_wait_for_motion() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M400").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::WaitForMotion);
+    }
+
+    #[test]
+    fn m115_get_firmware_info() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M115").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::GetFirmwareInfo);
+    }
+
+    #[test]
+    fn m119_get_endstop_status() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M119").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::GetEndstopStatus);
+    }
+
+    #[test]
+    fn supported_codes_are_known() {
+        for code in SUPPORTED_CODES {
+            // Some codes need more words than this, but none of them should be unknown
+            if let Err(nom::Err::Failure(e)) = command(XYZ, NO_PARAMS)(code.as_bytes()) {
+                assert!(
+                    !matches!(e.reason, Reason::UnknownGCode | Reason::UnknownMCode),
+                    "{code} is unknown"
+                );
+            }
+        }
+    }
+
     #[test]
     fn m17_enable_all_steppers() {
-        let (rem, res) = command(XYZF)(b"M17").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M17").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(res, Command::EnableAllSteppers);
     }
 
     #[test]
     fn m18_disable_all_steppers() {
-        let (rem, res) = command(XYZF)(b"M18").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"M18").unwrap();
         assert_eq!(rem, b"");
         assert_eq!(res, Command::DisableAllSteppers);
     }
 
     #[test]
     fn g28_home() {
-        let (rem, res) = command(XYZF)(b"G28").unwrap();
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"G28").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::Home([false; 3]));
+    }
+
+    #[test]
+    fn g28_home_axes() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G28 Z (only) X").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::Home([true, true, false]));
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G28 X X"),
+            Err(nom::Err::Failure(Error::new(b"X", Reason::RepeatedWord)))
+        );
+    }
+
+    #[test]
+    fn g28_home_axes_with_values() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G28 X0 Z0.5").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::Home([true, true, false]));
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G28 X1.2.3").unwrap();
+        // Left for the rest of the line, which fails to parse
+        assert_eq!(rem, b" X1.2.3");
+        assert_eq!(res, Command::Home([false; 3]));
+    }
+
+    #[test]
+    fn negative_coords() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G1 Z-2.5 C-10").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::LinearMove(
+                Pos([
+                    None,
+                    Some(FixedI32::lit("-2.5")),
+                    Some(FixedI32::lit("-10"))
+                ])
+                .into()
+            )
+        );
+    }
+
+    #[test]
+    fn inline_comments() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G0 (to the start) Z40 (and then) C10").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::RapidMove(
+                Pos([None, Some(FixedI32::lit("40")), Some(FixedI32::lit("10"))]).into()
+            )
+        );
+    }
+
+    #[test]
+    fn unterminated_comment_is_incomplete() {
+        let res = command(XZC, NO_PARAMS)(b"G0 Z40 (to the");
+        assert!(matches!(res, Err(nom::Err::Incomplete(_))));
+    }
+
+    #[test]
+    fn line_numbers() {
+        let (rem, res) = line_number(b"N123 ").unwrap();
+        assert_eq!(rem, b" ");
+        assert_eq!(res, 123);
+    }
+
+    #[test]
+    fn checksums() {
+        let (rem, res) = checksum(b"*71\n").unwrap();
+        assert_eq!(rem, b"\n");
+        assert_eq!(res, 71);
+    }
+
+    #[test]
+    fn g90_absolute() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"G90").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::SetDistanceMode(DistanceMode::Absolute));
+    }
+
+    #[test]
+    fn g91_relative() {
+        let (rem, res) = command(XYZ, NO_PARAMS)(b"G91").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::SetDistanceMode(DistanceMode::Relative));
+    }
+
+    #[test]
+    fn g92_set_position() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G92 C0 X10").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::SetPosition(Pos([
+                Some(FixedI32::lit("10")),
+                None,
+                Some(FixedI32::lit("0"))
+            ]))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G92 X1 X2"),
+            Err(nom::Err::Failure(Error::new(b"X2", Reason::RepeatedWord)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G92"),
+            Err(nom::Err::Failure(Error::new(b"G92", Reason::MissingAxis)))
+        );
+    }
+
+    #[test]
+    fn g20_g21_units() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G20"),
+            Ok((&b""[..], Command::SetUnits(Units::Inches)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G21"),
+            Ok((&b""[..], Command::SetUnits(Units::Millimeters)))
+        );
+    }
+
+    #[test]
+    fn g27_park() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G27"),
+            Ok((&b""[..], Command::Park(None)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G27 Z5"),
+            Ok((
+                &b""[..],
+                Command::Park(Some(Pos([None, Some(FixedI32::lit("5")), None])))
+            ))
+        );
+    }
+
+    #[test]
+    fn g27_1_set_park_position() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G27.1"),
+            Ok((&b""[..], Command::SetParkPosition(None)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G27.1 X1 Z5"),
+            Ok((
+                &b""[..],
+                Command::SetParkPosition(Some(Pos([
+                    Some(FixedI32::lit("1")),
+                    Some(FixedI32::lit("5")),
+                    None
+                ])))
+            ))
+        );
+    }
+
+    #[test]
+    fn lowercase() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"g1 z-2.5 f40").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(
+            res,
+            Command::LinearMove(Move {
+                target: Pos([None, Some(FixedI32::lit("-2.5")), None]),
+                feedrate: Some(UCoord::lit("40")),
+            })
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"g28 x"),
+            Ok((&b""[..], Command::Home([true, false, false])))
+        );
+        assert_eq!(line_number(b"n12 "), Ok((&b" "[..], 12)));
+    }
+
+    #[test]
+    fn compact_words() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G1X10Z5").unwrap();
         assert_eq!(rem, b"");
-        assert_eq!(res, Command::Home);
+        assert_eq!(
+            res,
+            Command::LinearMove(
+                Pos([Some(FixedI32::lit("10")), Some(FixedI32::lit("5")), None]).into()
+            )
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G28XZ"),
+            Ok((&b""[..], Command::Home([true, true, false])))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"G4P250"),
+            Ok((&b""[..], Command::Dwell(Duration::from_millis(250))))
+        );
+    }
+
+    #[test]
+    fn g4_fractional_secs() {
+        let (rem, res) = command(XZC, NO_PARAMS)(b"G4 S0.5").unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(res, Command::Dwell(Duration::from_millis(500)));
+
+        let (_, res) = command(XZC, NO_PARAMS)(b"G4 S2.1").unwrap();
+        assert_eq!(res, Command::Dwell(Duration::from_millis(2100)));
+    }
+
+    #[test]
+    fn o_word_subroutines() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O100 sub"),
+            Ok((&b""[..], Command::DefineSubroutine(100)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"o100 ENDSUB"),
+            Ok((&b""[..], Command::EndSubroutine(Some(100))))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O7call"),
+            Ok((&b""[..], Command::CallSubroutine(7)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O7 jump"),
+            Err(nom::Err::Failure(Error::new(
+                b"jump",
+                Reason::UnexpectedWord
+            )))
+        );
+    }
+
+    #[test]
+    fn m98_m99_subroutines() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"M98 P100"),
+            Ok((&b""[..], Command::CallSubroutine(100)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"M99"),
+            Ok((&b""[..], Command::EndSubroutine(None)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"M98"),
+            Err(nom::Err::Failure(Error::new(b"", Reason::UnexpectedWord)))
+        );
+    }
+
+    #[test]
+    fn o_word_loops() {
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O1 repeat [12]"),
+            Ok((&b""[..], Command::Repeat(1, 12)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"o1 REPEAT[ 3 ]"),
+            Ok((&b""[..], Command::Repeat(1, 3)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O1 repeat [4294967295]"),
+            Ok((&b""[..], Command::Repeat(1, u32::MAX)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O1 endrepeat"),
+            Ok((&b""[..], Command::EndRepeat(1)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O2 while [1]"),
+            Ok((&b""[..], Command::While(2, true)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O2 while [0.0]"),
+            Ok((&b""[..], Command::While(2, false)))
+        );
+        assert_eq!(
+            command(XZC, NO_PARAMS)(b"O2 endwhile"),
+            Ok((&b""[..], Command::EndWhile(2)))
+        );
+        assert!(matches!(
+            command::<3>(XZC, NO_PARAMS)(b"O1 repeat 12"),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
+    fn params() -> Parameters {
+        let mut params = Parameters::new();
+        params.set(1, ICoord::lit("0.13"));
+        params.set(2, ICoord::lit("10"));
+        params
+    }
+
+    fn x(coord: &str) -> Command<3> {
+        Command::LinearMove(Pos([Some(ICoord::from_str(coord).unwrap()), None, None]).into())
+    }
+
+    #[test]
+    fn parameters_and_expressions() {
+        let params = params();
+        let parse = |i: &'static [u8]| command(XZC, &params)(i).map(|(_, command)| command);
+        assert_eq!(parse(b"G1 X#1"), Ok(x("0.13")));
+        assert_eq!(parse(b"G1 X[#2 + #1 * 3]"), Ok(x("10.39")));
+        assert_eq!(parse(b"G1 X[ [#2 + 2] / -4 ]"), Ok(x("-3")));
+        assert_eq!(parse(b"G1 X[-#2 MOD 3]"), Ok(x("2")));
+        assert_eq!(parse(b"G1 X[#1 LT #2 AND #2 EQ 10]"), Ok(x("1")));
+        assert_eq!(
+            parse(b"G1 X[ABS[-2.5] + FIX[1.5] + ROUND[#1]]"),
+            Ok(x("3.5"))
+        );
+        assert_eq!(
+            parse(b"G4 P[#2 * 100]"),
+            Ok(Command::Dwell(Duration::from_millis(1000)))
+        );
+        assert_eq!(parse(b"O1 repeat [#2 / 2]"), Ok(Command::Repeat(1, 5)));
+        assert_eq!(parse(b"O2 while [#2 GT 20]"), Ok(Command::While(2, false)));
+        assert_eq!(parse(b"M98 P#2"), Ok(Command::CallSubroutine(10)));
+    }
+
+    #[test]
+    fn set_parameter() {
+        let params = params();
+        assert_eq!(
+            command(XZC, &params)(b"#3 = [#1 * 2]"),
+            Ok((&b""[..], Command::SetParameter(3, ICoord::lit("0.26"))))
+        );
+        assert_eq!(
+            command(XZC, &params)(b"#3=-1"),
+            Ok((&b""[..], Command::SetParameter(3, ICoord::lit("-1"))))
+        );
+    }
+
+    #[test]
+    fn expression_errors() {
+        let params = params();
+        let reason = |i: &'static [u8]| match command::<3>(XZC, &params)(i) {
+            Err(nom::Err::Failure(e)) => e.reason,
+            res => panic!("{res:?}"),
+        };
+        assert_eq!(reason(b"G1 X#3"), Reason::UndefinedParameter);
+        assert_eq!(reason(b"G1 X#100"), Reason::UnknownParameter);
+        assert_eq!(reason(b"#0 = 1"), Reason::UnknownParameter);
+        assert_eq!(reason(b"G1 X[#2 / 0]"), Reason::DivisionByZero);
+        assert_eq!(reason(b"G1 X[#2 * 1000000]"), Reason::NumberOutOfRange);
+        assert_eq!(reason(b"G1 F[-1]"), Reason::NumberOutOfRange);
+        assert_eq!(reason(b"O1 repeat [-1]"), Reason::NumberOutOfRange);
+        assert_eq!(reason(b"G1 X[#2 +]"), Reason::BadNumber);
+        assert_eq!(reason(b"G1 X[#2"), Reason::UnexpectedWord);
+        let nested = format!("G1 X{}1{}", "[".repeat(20), "]".repeat(20));
+        assert_eq!(
+            match command::<3>(XZC, &params)(nested.as_bytes()) {
+                Err(nom::Err::Failure(e)) => e.reason,
+                res => panic!("{res:?}"),
+            },
+            Reason::NestedTooDeeply
+        );
     }
 }
@@ -0,0 +1,268 @@
+use std::{fmt, ops::Range, vec::Vec};
+
+use crate::{parse_line, Command, Error, Line, Parameters, ParseError};
+
+/// A line of a gcode program, along with where it came from in the source
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ProgramLine<'a, const AXES: usize> {
+    /// The line's position in the source, counting from 1 (as opposed to its `N` word, if it has
+    /// one, which is in `line.number`)
+    pub line_number: usize,
+    /// Byte range of the line in the source, not including the line ending
+    pub span: Range<usize>,
+    pub line: Line<AXES>,
+    /// The text inside each comment on the line, in order
+    pub comments: Vec<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct ProgramError<'a> {
+    /// The line the error is on, counting from 1
+    pub line_number: usize,
+    /// Byte range of the line in the source, not including the line ending
+    pub line_span: Range<usize>,
+    pub error: Error<'a>,
+}
+
+impl ProgramError<'_> {
+    /// Byte range in the source of the word the error was found at, or of the whole line if the
+    /// error isn't about any particular word
+    pub fn span(&self) -> Range<usize> {
+        match &self.error {
+            Error::ParseFailed(e) => {
+                let start = self.line_span.start + e.offset;
+                start..start + e.word.len()
+            }
+            Error::ChecksumMismatch | Error::MissingChecksum | Error::Incomplete(_) => {
+                self.line_span.clone()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ProgramError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_number, self.error)
+    }
+}
+
+/// The text inside each of the comments on a line that's already been parsed
+fn comments(line: &str) -> Vec<&str> {
+    let mut comments = Vec::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(['(', ';']) {
+        let (delimiter, comment) = rest[start..].split_at(1);
+        if delimiter == ";" {
+            comments.push(comment.trim());
+            break;
+        }
+        let end = comment.find(')').unwrap_or(comment.len());
+        comments.push(comment[..end].trim());
+        rest = comment.get(end + 1..).unwrap_or_default();
+    }
+    comments
+}
+
+/// Parse a whole program, returning either every line or every error.
+///
+/// Parameters are set and used in the order the lines appear, without running any loops or
+/// subroutine calls. The bodies of subroutines and loops are only evaluated when they're run, with
+/// whatever the parameters are by then, so their lines are only checked for syntax, the same way
+/// the firmware records them. Their values come back as zero
+pub fn parse_program<const AXES: usize>(
+    axis_labels: [char; AXES],
+    source: &str,
+) -> Result<Vec<ProgramLine<'_, AXES>>, Vec<ProgramError<'_>>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    let mut start = 0;
+    let mut parameters = Parameters::new();
+    // The subroutine or loop whose body we're in, if any
+    let mut block = None;
+    for (i, text) in source.split_inclusive('\n').enumerate() {
+        let line_number = i + 1;
+        let content = text
+            .strip_suffix("\r\n")
+            .or_else(|| text.strip_suffix('\n'))
+            .unwrap_or(text);
+        let span = start..start + content.len();
+        start += text.len();
+
+        // The last line might not have a line ending, but the parser needs one
+        let terminated;
+        let input = if text.ends_with('\n') {
+            text.as_bytes()
+        } else {
+            terminated = [content.as_bytes(), b"\n"].concat();
+            &terminated[..]
+        };
+
+        let line_parameters = match block {
+            Some(_) => &Parameters::UNEVALUATED,
+            None => &parameters,
+        };
+        // Lines in a program don't need checksums, even if they're numbered
+        match parse_line(line_parameters, axis_labels, input, false) {
+            Ok((_, line)) => {
+                match (block, line.command) {
+                    (None, Command::SetParameter(number, value)) => parameters.set(number, value),
+                    (
+                        None,
+                        Command::DefineSubroutine(id)
+                        | Command::Repeat(id, _)
+                        | Command::While(id, _),
+                    ) => block = Some(id),
+                    (Some(id), Command::EndSubroutine(end)) if end.is_none_or(|end| end == id) => {
+                        block = None
+                    }
+                    (Some(id), Command::EndRepeat(end) | Command::EndWhile(end)) if end == id => {
+                        block = None
+                    }
+                    _ => {}
+                }
+                lines.push(ProgramLine {
+                    line_number,
+                    span,
+                    line,
+                    comments: comments(content),
+                })
+            }
+            Err(error) => {
+                // Point back into the source, rather than at our copy of the line
+                let error = match error {
+                    Error::ParseFailed(e) => Error::ParseFailed(ParseError {
+                        word: &text.as_bytes()[e.offset..e.offset + e.word.len()],
+                        ..e
+                    }),
+                    Error::ChecksumMismatch => Error::ChecksumMismatch,
+                    Error::MissingChecksum => Error::MissingChecksum,
+                    Error::Incomplete(needed) => Error::Incomplete(needed),
+                };
+                errors.push(ProgramError {
+                    line_number,
+                    line_span: span,
+                    error,
+                });
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ICoord, Pos, Reason};
+
+    const XZC: [char; 3] = ['X', 'Z', 'C'];
+
+    #[test]
+    fn lines_and_spans() {
+        let source = "(first layer)\r\nN1 M17 ; wake up\n\nG28 X (home) ; then wind";
+        let lines = parse_program(XZC, source).unwrap();
+        assert_eq!(lines.len(), 4);
+
+        assert_eq!(lines[0].line_number, 1);
+        assert_eq!(&source[lines[0].span.clone()], "(first layer)");
+        assert_eq!(lines[0].line.command, Command::Comment);
+        assert_eq!(lines[0].comments, ["first layer"]);
+
+        assert_eq!(lines[1].line_number, 2);
+        assert_eq!(&source[lines[1].span.clone()], "N1 M17 ; wake up");
+        assert_eq!(lines[1].line.number, Some(1));
+        assert_eq!(lines[1].line.command, Command::EnableAllSteppers);
+        assert_eq!(lines[1].comments, ["wake up"]);
+
+        assert_eq!(lines[2].span, 32..32);
+        assert!(lines[2].comments.is_empty());
+
+        assert_eq!(lines[3].line_number, 4);
+        assert_eq!(&source[lines[3].span.clone()], "G28 X (home) ; then wind");
+        assert_eq!(lines[3].line.command, Command::Home([true, false, false]));
+        assert_eq!(lines[3].comments, ["home", "then wind"]);
+    }
+
+    #[test]
+    fn every_error() {
+        let source = "G1 Zfoo\nM17\nG5\nG1 X1.2.3";
+        let errors = parse_program(XZC, source).unwrap_err();
+        assert_eq!(errors.len(), 3);
+
+        assert_eq!(errors[0].line_number, 1);
+        assert_eq!(&source[errors[0].span()], "Zfoo");
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1: bad number \"Zfoo\" at byte 3"
+        );
+
+        assert_eq!(errors[1].line_number, 3);
+        assert!(matches!(
+            errors[1].error,
+            Error::ParseFailed(ParseError {
+                reason: Reason::UnknownGCode,
+                ..
+            })
+        ));
+
+        // The last line has no line ending
+        assert_eq!(errors[2].line_number, 4);
+        assert_eq!(&source[errors[2].span()], ".3");
+    }
+
+    #[test]
+    fn stray_carriage_returns() {
+        let cases = [
+            ("G1 X1\r\r\n", "G1 X1\r"),
+            ("G1 X1 \r\r\n", "G1 X1 \r"),
+            ("\r\r", "\r\r"),
+        ];
+        for (source, line) in cases {
+            let errors = parse_program(XZC, source).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            // Only the one line ending is left off the line
+            assert_eq!(&source[errors[0].line_span.clone()], line);
+            assert!(errors[0].span().end <= errors[0].line_span.end);
+        }
+    }
+
+    #[test]
+    fn parameters() {
+        let source = "#1 = 0.5\nG1 X[#1 * 4]\nG1 X#2\n";
+        let errors = parse_program(XZC, source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_number, 3);
+        assert_eq!(&source[errors[0].span()], "#2");
+
+        let lines = parse_program(XZC, "#1 = 0.5\nG1 X[#1 * 4]\n").unwrap();
+        assert_eq!(
+            lines[1].line.command,
+            Command::LinearMove(Pos([Some(ICoord::lit("2")), None, None]).into())
+        );
+    }
+
+    #[test]
+    fn blocks_are_evaluated_when_run() {
+        // The subroutine uses a parameter that's only set before it's called
+        let source = "O100 sub\nG1 Z[#1]\nO100 endsub\n#1 = 0.13\nO100 call\n";
+        let lines = parse_program(XZC, source).unwrap();
+        assert_eq!(lines.len(), 5);
+
+        // A parameter set inside a loop isn't applied, since its value depends on the loop running
+        let source = "#1 = 0\nO1 while [#1 LT 3]\n#1 = [#1 + 1]\nO1 endwhile\nG1 X[#1]\n";
+        let lines = parse_program(XZC, source).unwrap();
+        assert_eq!(lines[1].line.command, Command::While(1, true));
+        assert_eq!(
+            lines[4].line.command,
+            Command::LinearMove(Pos([Some(ICoord::ZERO), None, None]).into())
+        );
+
+        // Syntax is still checked inside a block
+        let errors = parse_program(XZC, "O1 sub\nG1 Zfoo\nO1 endsub\n").unwrap_err();
+        assert_eq!(errors[0].line_number, 2);
+    }
+}
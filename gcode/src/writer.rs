@@ -0,0 +1,412 @@
+use core::fmt;
+
+use crate::{
+    ast::{Command, DistanceMode, ICoord, Move, Pos, UCoord, Units},
+    expr::PARAMETERS,
+};
+
+/// Displays a [`Command`] as a line of gcode (without the trailing newline), which parses back to
+/// the same command with [`crate::parse_single_command`] given the same axis labels.
+///
+/// Commands the parser would never produce fail with [`fmt::Error`] instead of writing a line that
+/// parses to something else, or not at all. Those are:
+/// - dwells that aren't a whole number of milliseconds, or are longer than `u64::MAX` of them
+/// - moves with no words, and G27, G27.1 or G92 with `Some` position but no axes in it
+/// - steps per unit, accelerations and feedrate limits that aren't more than zero, and M201 or
+///   M203 without any axes
+/// - parameters numbered outside of `1..PARAMETERS`
+pub struct CommandDisplay<'a, const AXES: usize> {
+    command: &'a Command<AXES>,
+    axis_labels: [char; AXES],
+}
+
+impl<const AXES: usize> Command<AXES> {
+    pub fn display(&self, axis_labels: [char; AXES]) -> CommandDisplay<'_, AXES> {
+        CommandDisplay {
+            command: self,
+            axis_labels,
+        }
+    }
+}
+
+fn write_position<const AXES: usize>(
+    f: &mut fmt::Formatter<'_>,
+    axis_labels: [char; AXES],
+    pos: &Pos<AXES>,
+) -> fmt::Result {
+    for (label, coord) in axis_labels.iter().zip(pos.0) {
+        if let Some(coord) = coord {
+            write!(f, " {label}{coord}")?;
+        }
+    }
+    Ok(())
+}
+
+/// A position that's given has to have at least one axis in it, since one without any wouldn't
+/// parse as a position at all
+fn non_empty<const AXES: usize>(pos: &Pos<AXES>) -> fmt::Result {
+    if pos.0.iter().all(Option::is_none) {
+        return Err(fmt::Error);
+    }
+    Ok(())
+}
+
+/// Values like steps per unit or speed limits are only accepted above zero
+fn positive<const AXES: usize>(pos: &Pos<AXES>) -> fmt::Result {
+    if pos.0.iter().flatten().any(|value| *value <= ICoord::ZERO) {
+        return Err(fmt::Error);
+    }
+    Ok(())
+}
+
+fn write_move<const AXES: usize>(
+    f: &mut fmt::Formatter<'_>,
+    axis_labels: [char; AXES],
+    mv: &Move<AXES>,
+) -> fmt::Result {
+    if mv.target.0.iter().all(Option::is_none) && mv.feedrate.is_none() {
+        return Err(fmt::Error);
+    }
+    write_position(f, axis_labels, &mv.target)?;
+    if let Some(feedrate) = mv.feedrate {
+        write!(f, " F{feedrate}")?;
+    }
+    Ok(())
+}
+
+impl<const AXES: usize> fmt::Display for CommandDisplay<'_, AXES> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.command {
+            Command::Comment => Ok(()),
+            Command::RapidMove(mv) => {
+                f.write_str("G0")?;
+                write_move(f, self.axis_labels, mv)
+            }
+            Command::LinearMove(mv) => {
+                f.write_str("G1")?;
+                write_move(f, self.axis_labels, mv)
+            }
+            Command::Dwell(duration) => {
+                let millis = u64::try_from(duration.as_millis()).map_err(|_| fmt::Error)?;
+                if duration.subsec_nanos() % 1_000_000 != 0 {
+                    return Err(fmt::Error);
+                }
+                // Seconds have to fit in a UCoord, but milliseconds can be as big as a u64
+                if duration.subsec_millis() == 0
+                    && duration.as_secs() <= UCoord::MAX.to_num::<u64>()
+                {
+                    write!(f, "G4 S{}", duration.as_secs())
+                } else {
+                    write!(f, "G4 P{millis}")
+                }
+            }
+            Command::SetUnits(Units::Inches) => f.write_str("G20"),
+            Command::SetUnits(Units::Millimeters) => f.write_str("G21"),
+            Command::Park(pos) => {
+                f.write_str("G27")?;
+                match pos {
+                    Some(pos) => {
+                        non_empty(pos)?;
+                        write_position(f, self.axis_labels, pos)
+                    }
+                    None => Ok(()),
+                }
+            }
+            Command::SetParkPosition(pos) => {
+                f.write_str("G27.1")?;
+                match pos {
+                    Some(pos) => {
+                        non_empty(pos)?;
+                        write_position(f, self.axis_labels, pos)
+                    }
+                    None => Ok(()),
+                }
+            }
+            Command::Home(axes) => {
+                f.write_str("G28")?;
+                for (label, home) in self.axis_labels.iter().zip(axes) {
+                    if *home {
+                        write!(f, " {label}")?;
+                    }
+                }
+                Ok(())
+            }
+            Command::SetDistanceMode(DistanceMode::Absolute) => f.write_str("G90"),
+            Command::SetDistanceMode(DistanceMode::Relative) => f.write_str("G91"),
+            Command::SetPosition(pos) => {
+                non_empty(pos)?;
+                f.write_str("G92")?;
+                write_position(f, self.axis_labels, pos)
+            }
+            Command::Stop => f.write_str("M0"),
+            Command::EnableAllSteppers => f.write_str("M17"),
+            Command::DisableAllSteppers => f.write_str("M18"),
+            Command::SetStepsPerUnit(steps) => {
+                positive(steps)?;
+                f.write_str("M92")?;
+                write_position(f, self.axis_labels, steps)
+            }
+            Command::SetMaxAcceleration(accelerations) => {
+                non_empty(accelerations)?;
+                positive(accelerations)?;
+                f.write_str("M201")?;
+                write_position(f, self.axis_labels, accelerations)
+            }
+            Command::SetMaxFeedrate(feedrates) => {
+                non_empty(feedrates)?;
+                positive(feedrates)?;
+                f.write_str("M203")?;
+                write_position(f, self.axis_labels, feedrates)
+            }
+            Command::SetAcceleration(acceleration) if *acceleration > UCoord::ZERO => {
+                write!(f, "M204 S{acceleration}")
+            }
+            Command::SetAcceleration(_) => Err(fmt::Error),
+            Command::EmergencyStop => f.write_str("M112"),
+            Command::GetCurrentPosition => f.write_str("M114"),
+            Command::GetFirmwareInfo => f.write_str("M115"),
+            Command::GetEndstopStatus => f.write_str("M119"),
+            Command::WaitForMotion => f.write_str("M400"),
+            Command::Reset => f.write_str("M999"),
+            Command::DefineSubroutine(id) => write!(f, "O{id} sub"),
+            Command::EndSubroutine(Some(id)) => write!(f, "O{id} endsub"),
+            Command::EndSubroutine(None) => f.write_str("M99"),
+            Command::CallSubroutine(id) => write!(f, "O{id} call"),
+            Command::Repeat(id, count) => write!(f, "O{id} repeat [{count}]"),
+            Command::EndRepeat(id) => write!(f, "O{id} endrepeat"),
+            Command::While(id, condition) => write!(f, "O{id} while [{}]", u8::from(*condition)),
+            Command::EndWhile(id) => write!(f, "O{id} endwhile"),
+            Command::SetParameter(number, value) if (1..PARAMETERS as u32).contains(number) => {
+                write!(f, "#{number} = {value}")
+            }
+            Command::SetParameter(..) => Err(fmt::Error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::*;
+    use crate::{parse_single_command, ICoord};
+
+    const XZC: [char; 3] = ['X', 'Z', 'C'];
+
+    fn round_trip(command: Command<3>) -> String {
+        let line = format!("{}\n", command.display(XZC));
+        let (rem, parsed) = parse_single_command(XZC, line.as_bytes()).unwrap();
+        assert_eq!(rem, b"");
+        assert_eq!(parsed.command, command, "{line:?} didn't round-trip");
+        line
+    }
+
+    #[test]
+    fn moves() {
+        assert_eq!(
+            round_trip(Command::RapidMove(Move {
+                target: Pos([None, Some(ICoord::lit("43")), Some(ICoord::lit("-1.5"))]),
+                feedrate: Some(UCoord::lit("20")),
+            })),
+            "G0 Z43 C-1.5 F20\n"
+        );
+        assert_eq!(
+            round_trip(Command::LinearMove(
+                Pos::from([ICoord::lit("0.25"); 3]).into()
+            )),
+            "G1 X0.25 Z0.25 C0.25\n"
+        );
+    }
+
+    #[test]
+    fn coordinates() {
+        // Every fixed-point value should come back exactly, not just the ones with short decimal
+        // representations
+        for bits in (i32::MIN..=i32::MAX).step_by(65_521) {
+            let coord = ICoord::from_bits(bits);
+            round_trip(Command::LinearMove(Pos([Some(coord), None, None]).into()));
+        }
+        round_trip(Command::LinearMove(
+            Pos([Some(ICoord::MAX), None, None]).into(),
+        ));
+        round_trip(Command::LinearMove(
+            Pos([Some(ICoord::MIN), None, None]).into(),
+        ));
+        round_trip(Command::LinearMove(Move {
+            target: Pos([None; 3]),
+            feedrate: Some(UCoord::MAX),
+        }));
+    }
+
+    #[test]
+    fn set_position() {
+        assert_eq!(
+            round_trip(Command::SetPosition(Pos([
+                Some(ICoord::ZERO),
+                None,
+                Some(ICoord::lit("-3"))
+            ]))),
+            "G92 X0 C-3\n"
+        );
+    }
+
+    #[test]
+    fn park() {
+        assert_eq!(round_trip(Command::Park(None)), "G27\n");
+        assert_eq!(
+            round_trip(Command::Park(Some(Pos([
+                Some(ICoord::lit("10")),
+                None,
+                None
+            ])))),
+            "G27 X10\n"
+        );
+        assert_eq!(round_trip(Command::SetParkPosition(None)), "G27.1\n");
+        assert_eq!(
+            round_trip(Command::SetParkPosition(Some(Pos([
+                None,
+                Some(ICoord::lit("2.5")),
+                None
+            ])))),
+            "G27.1 Z2.5\n"
+        );
+    }
+
+    #[test]
+    fn dwell() {
+        assert_eq!(
+            round_trip(Command::Dwell(Duration::from_secs(4))),
+            "G4 S4\n"
+        );
+        assert_eq!(
+            round_trip(Command::Dwell(Duration::from_millis(1500))),
+            "G4 P1500\n"
+        );
+        assert_eq!(
+            round_trip(Command::Dwell(Duration::from_secs(1 << 32))),
+            "G4 P4294967296000\n"
+        );
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(round_trip(Command::Comment), "\n");
+        assert_eq!(round_trip(Command::SetUnits(Units::Inches)), "G20\n");
+        assert_eq!(round_trip(Command::SetUnits(Units::Millimeters)), "G21\n");
+        assert_eq!(round_trip(Command::Home([false; 3])), "G28\n");
+        assert_eq!(round_trip(Command::Home([false, true, false])), "G28 Z\n");
+        assert_eq!(
+            round_trip(Command::SetDistanceMode(DistanceMode::Absolute)),
+            "G90\n"
+        );
+        assert_eq!(
+            round_trip(Command::SetDistanceMode(DistanceMode::Relative)),
+            "G91\n"
+        );
+        assert_eq!(round_trip(Command::Stop), "M0\n");
+        assert_eq!(round_trip(Command::EnableAllSteppers), "M17\n");
+        assert_eq!(round_trip(Command::DisableAllSteppers), "M18\n");
+        assert_eq!(
+            round_trip(Command::SetStepsPerUnit(Pos([
+                Some(ICoord::from_num(80)),
+                None,
+                Some(ICoord::from_num(3200)),
+            ]))),
+            "M92 X80 C3200\n"
+        );
+        assert_eq!(
+            round_trip(Command::SetStepsPerUnit(Pos([None; 3]))),
+            "M92\n"
+        );
+        assert_eq!(
+            round_trip(Command::SetMaxAcceleration(Pos([
+                None,
+                Some(ICoord::from_num(500)),
+                None,
+            ]))),
+            "M201 Z500\n"
+        );
+        assert_eq!(
+            round_trip(Command::SetMaxFeedrate(Pos([
+                Some(ICoord::from_num(50)),
+                None,
+                Some(ICoord::lit("2.5")),
+            ]))),
+            "M203 X50 C2.5\n"
+        );
+        assert_eq!(
+            round_trip(Command::SetAcceleration(UCoord::from_num(200))),
+            "M204 S200\n"
+        );
+        assert_eq!(round_trip(Command::EmergencyStop), "M112\n");
+        assert_eq!(round_trip(Command::GetCurrentPosition), "M114\n");
+        assert_eq!(round_trip(Command::GetFirmwareInfo), "M115\n");
+        assert_eq!(round_trip(Command::GetEndstopStatus), "M119\n");
+        assert_eq!(round_trip(Command::WaitForMotion), "M400\n");
+        assert_eq!(round_trip(Command::Reset), "M999\n");
+    }
+
+    #[test]
+    fn subroutines() {
+        assert_eq!(round_trip(Command::DefineSubroutine(100)), "O100 sub\n");
+        assert_eq!(
+            round_trip(Command::EndSubroutine(Some(100))),
+            "O100 endsub\n"
+        );
+        assert_eq!(round_trip(Command::EndSubroutine(None)), "M99\n");
+        assert_eq!(round_trip(Command::CallSubroutine(100)), "O100 call\n");
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(round_trip(Command::Repeat(1, 12)), "O1 repeat [12]\n");
+        assert_eq!(
+            round_trip(Command::Repeat(1, u32::MAX)),
+            "O1 repeat [4294967295]\n"
+        );
+        assert_eq!(round_trip(Command::EndRepeat(1)), "O1 endrepeat\n");
+        assert_eq!(round_trip(Command::While(2, true)), "O2 while [1]\n");
+        assert_eq!(round_trip(Command::While(2, false)), "O2 while [0]\n");
+        assert_eq!(round_trip(Command::EndWhile(2)), "O2 endwhile\n");
+    }
+
+    #[test]
+    fn set_parameter() {
+        assert_eq!(
+            round_trip(Command::SetParameter(1, ICoord::lit("-0.125"))),
+            "#1 = -0.125\n"
+        );
+    }
+
+    #[test]
+    fn unparseable_commands_fail() {
+        use core::fmt::Write;
+
+        let fails = |command: Command<3>| {
+            let mut line = String::new();
+            assert!(
+                write!(line, "{}", command.display(XZC)).is_err(),
+                "{command:?} was written as {line:?}"
+            );
+        };
+        fails(Command::Dwell(Duration::from_micros(1500)));
+        fails(Command::Dwell(Duration::from_secs(u64::MAX)));
+        fails(Command::LinearMove(Pos([None; 3]).into()));
+        fails(Command::Park(Some(Pos([None; 3]))));
+        fails(Command::SetPosition(Pos([None; 3])));
+        fails(Command::SetStepsPerUnit(Pos([
+            Some(ICoord::lit("-1")),
+            None,
+            None,
+        ])));
+        fails(Command::SetMaxFeedrate(Pos([
+            None,
+            Some(ICoord::ZERO),
+            None,
+        ])));
+        fails(Command::SetMaxAcceleration(Pos([None; 3])));
+        fails(Command::SetAcceleration(UCoord::ZERO));
+        fails(Command::SetParameter(0, ICoord::ONE));
+        fails(Command::SetParameter(PARAMETERS as u32, ICoord::ONE));
+    }
+}
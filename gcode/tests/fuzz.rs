@@ -0,0 +1,225 @@
+//! Property tests checking that no input, however mangled, can panic the parser - the firmware
+//! parses whatever arrives over the network
+
+use std::time::Duration;
+
+use gcode::{
+    parse_single_command, parse_single_command_with, Command, DistanceMode, Error, ICoord, Move,
+    Parameters, Pos, Reason, UCoord, Units, PARAMETERS,
+};
+use proptest::prelude::*;
+
+const XZC: [char; 3] = ['X', 'Z', 'C'];
+
+/// Inputs that have broken (or nearly broken) the parser before
+const CORPUS: &[&[u8]] = &[
+    b"G1 X-\n",
+    b"G1 X.\n",
+    b"G1 X-.\n",
+    b"G1 X1e5\n",
+    b"G1 X1E999\n",
+    b"G1 X+\n",
+    b"G1 X99999999999999999999999999\n",
+    b"G1 X-99999999999999999999999999\n",
+    b"G1 X0.000000000000000000000000001\n",
+    b"G1 F-1\n",
+    b"G1 F-0\n",
+    b"G4 S-1\n",
+    b"G4 S4294967296\n",
+    b"G4 P99999999999999999999999\n",
+    b"G4\n",
+    b"N\n",
+    b"N99999999999 M17\n",
+    b"N1 M17*\n",
+    b"N1 M17*999\n",
+    b"(\n",
+    b")\n",
+    b";\n",
+    b"*\n",
+    b"\r\n",
+    b"\r\r\n",
+    b"G\n",
+    b"M\n",
+    b"G99999999999999999999\n",
+    b"\xff\xfe\n",
+    b"G1 X\xff\n",
+    b"G28 X X\n",
+    b"G28 X1\n",
+    b"G92\n",
+    b"O\n",
+    b"O99999999999 call\n",
+    b"O1\n",
+    b"M98 P-1\n",
+    b"O1 repeat [\n",
+    b"O1 repeat [4294967296]\n",
+    b"O1 while []\n",
+    b"#\n",
+    b"#1\n",
+    b"#1 =\n",
+    b"#99999999999 = 1\n",
+    b"G1 X[\n",
+    b"G1 X[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[[1]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]]\n",
+    b"G1 X[1 MOD 0]\n",
+    b"G1 X[-2097152 / -1]\n",
+    b"G1 X--------------1\n",
+];
+
+#[test]
+fn corpus() {
+    for input in CORPUS {
+        // Just mustn't panic
+        let _ = parse_single_command(XZC, input);
+    }
+}
+
+#[test]
+fn numbers_out_of_range() {
+    let error = |input: &'static [u8]| match parse_single_command(XZC, input) {
+        Err(Error::ParseFailed(e)) => e.reason,
+        res => panic!("expected a parse failure for {input:?}, got {res:?}"),
+    };
+    assert_eq!(error(b"G1 X9999999\n"), Reason::NumberOutOfRange);
+    assert_eq!(error(b"G1 X-9999999\n"), Reason::NumberOutOfRange);
+    assert_eq!(error(b"G1 F-1\n"), Reason::NumberOutOfRange);
+    assert_eq!(error(b"G4 S-1\n"), Reason::NumberOutOfRange);
+    assert_eq!(error(b"G1 X1e5\n"), Reason::UnexpectedWord);
+    assert_eq!(error(b"G1 X-\n"), Reason::BadNumber);
+}
+
+fn icoord() -> impl Strategy<Value = ICoord> {
+    any::<i32>().prop_map(ICoord::from_bits)
+}
+
+fn ucoord() -> impl Strategy<Value = UCoord> {
+    any::<u32>().prop_map(UCoord::from_bits)
+}
+
+fn position() -> impl Strategy<Value = Pos<3>> {
+    proptest::array::uniform3(proptest::option::of(icoord())).prop_map(Pos)
+}
+
+fn mv() -> impl Strategy<Value = Move<3>> {
+    (position(), proptest::option::of(ucoord()))
+        .prop_map(|(target, feedrate)| Move { target, feedrate })
+}
+
+fn duration() -> impl Strategy<Value = Duration> {
+    prop_oneof![
+        any::<u64>().prop_map(Duration::from_millis),
+        any::<u32>().prop_map(|secs| Duration::from_secs(secs.into())),
+        any::<(u64, u32)>().prop_map(|(secs, nanos)| Duration::new(secs, nanos % 1_000_000_000)),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command<3>> {
+    prop_oneof![
+        Just(Command::Comment),
+        mv().prop_map(Command::RapidMove),
+        mv().prop_map(Command::LinearMove),
+        duration().prop_map(Command::Dwell),
+        Just(Command::SetUnits(Units::Inches)),
+        Just(Command::SetUnits(Units::Millimeters)),
+        proptest::option::of(position()).prop_map(Command::Park),
+        proptest::option::of(position()).prop_map(Command::SetParkPosition),
+        any::<[bool; 3]>().prop_map(Command::Home),
+        Just(Command::SetDistanceMode(DistanceMode::Absolute)),
+        Just(Command::SetDistanceMode(DistanceMode::Relative)),
+        position().prop_map(Command::SetPosition),
+        Just(Command::Stop),
+        Just(Command::EnableAllSteppers),
+        Just(Command::DisableAllSteppers),
+        position().prop_map(Command::SetStepsPerUnit),
+        position().prop_map(Command::SetMaxAcceleration),
+        position().prop_map(Command::SetMaxFeedrate),
+        ucoord().prop_map(Command::SetAcceleration),
+        Just(Command::EmergencyStop),
+        Just(Command::GetCurrentPosition),
+        Just(Command::GetFirmwareInfo),
+        Just(Command::GetEndstopStatus),
+        Just(Command::WaitForMotion),
+        Just(Command::Reset),
+        any::<u32>().prop_map(Command::DefineSubroutine),
+        any::<Option<u32>>().prop_map(Command::EndSubroutine),
+        any::<u32>().prop_map(Command::CallSubroutine),
+        any::<(u32, u32)>().prop_map(|(id, count)| Command::Repeat(id, count)),
+        any::<u32>().prop_map(Command::EndRepeat),
+        any::<(u32, bool)>().prop_map(|(id, condition)| Command::While(id, condition)),
+        any::<u32>().prop_map(Command::EndWhile),
+        (0..=PARAMETERS as u32, icoord())
+            .prop_map(|(number, value)| Command::SetParameter(number, value)),
+    ]
+}
+
+/// Whether the parser could have produced a command, which is exactly when it can be written
+fn parseable(command: &Command<3>) -> bool {
+    let non_empty = |pos: &Pos<3>| pos.0.iter().any(Option::is_some);
+    let positive = |pos: &Pos<3>| pos.0.iter().flatten().all(|value| *value > ICoord::ZERO);
+    match command {
+        Command::Dwell(duration) => {
+            duration.subsec_nanos() % 1_000_000 == 0 && u64::try_from(duration.as_millis()).is_ok()
+        }
+        Command::RapidMove(mv) | Command::LinearMove(mv) => {
+            non_empty(&mv.target) || mv.feedrate.is_some()
+        }
+        Command::Park(Some(pos))
+        | Command::SetParkPosition(Some(pos))
+        | Command::SetPosition(pos) => non_empty(pos),
+        Command::SetStepsPerUnit(steps) => positive(steps),
+        Command::SetMaxAcceleration(limits) | Command::SetMaxFeedrate(limits) => {
+            non_empty(limits) && positive(limits)
+        }
+        Command::SetAcceleration(acceleration) => *acceleration > UCoord::ZERO,
+        Command::SetParameter(number, _) => (1..PARAMETERS as u32).contains(number),
+        _ => true,
+    }
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_dont_panic(mut input in proptest::collection::vec(any::<u8>(), 0..64)) {
+        input.push(b'\n');
+        let _ = parse_single_command(XZC, &input);
+    }
+
+    #[test]
+    fn gcode_like_lines_dont_panic(input in "[GMNOXZCFSPgmnoxzc0-9 .+*;()\r-]{0,40}(sub|call|endsub|repeat|while|\\[|#)?\n") {
+        let _ = parse_single_command(XZC, input.as_bytes());
+    }
+
+    #[test]
+    fn numbers_dont_panic(number in "[-+]?[0-9]{0,12}(\\.[0-9]{0,12})?([eE][-+]?[0-9]{0,4})?") {
+        for word in ["X", "F", "S", "P", "N", "G"] {
+            let _ = parse_single_command(XZC, format!("G1 {word}{number}\n").as_bytes());
+            let _ = parse_single_command(XZC, format!("G4 {word}{number}\n").as_bytes());
+        }
+    }
+
+    #[test]
+    fn expressions_dont_panic(
+        expr in "([-+*/#0-9 .\\[\\]]|MOD|EQ|LT|AND|XOR|ABS|FUP|ROUND){0,30}",
+        values in proptest::collection::vec(icoord(), PARAMETERS),
+    ) {
+        let mut params = Parameters::new();
+        for (number, value) in values.into_iter().enumerate() {
+            params.set(number as u32, value);
+        }
+        let _ = parse_single_command_with(&params, XZC, format!("G1 X[{expr}]\n").as_bytes());
+        let _ = parse_single_command_with(&params, XZC, format!("#1 = {expr}\n").as_bytes());
+    }
+
+    #[test]
+    fn commands_round_trip(command in command()) {
+        use std::fmt::Write;
+
+        let mut line = String::new();
+        let written = write!(line, "{}", command.display(XZC));
+        prop_assert_eq!(written.is_ok(), parseable(&command), "{:?}", line);
+        if written.is_err() {
+            return Ok(());
+        }
+        line.push('\n');
+        let (rest, parsed) = parse_single_command(XZC, line.as_bytes()).unwrap();
+        prop_assert_eq!(rest, b"");
+        prop_assert_eq!(parsed.command, command);
+    }
+}